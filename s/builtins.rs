@@ -0,0 +1,1009 @@
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinKind {
+    Verb,
+    Adverb,
+    Function,
+    Form,
+    System,
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub kind: BuiltinKind,
+    pub summary: &'static str,
+    pub monadic: Option<&'static str>,
+    pub dyadic: Option<&'static str>,
+    pub example: &'static str,
+}
+
+impl Builtin {
+    pub fn markdown(&self) -> String {
+        let mut markdown = format!("`{}` {}\n\n", self.name, self.summary);
+        if let Some(monadic) = self.monadic {
+            markdown.push_str(&format!("- monadic: {monadic}\n"));
+        }
+        if let Some(dyadic) = self.dyadic {
+            markdown.push_str(&format!("- dyadic: {dyadic}\n"));
+        }
+        markdown.push_str(&format!("\n```k\n{}\n```", self.example));
+        markdown
+    }
+}
+
+pub fn verbs() -> impl Iterator<Item = &'static Builtin> {
+    BUILTINS
+        .iter()
+        .filter(|builtin| builtin.kind == BuiltinKind::Verb)
+}
+
+pub static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: ":",
+        kind: BuiltinKind::Verb,
+        summary: "return / assign",
+        monadic: Some("`:x` return x from the enclosing lambda"),
+        dyadic: Some("`x:y` assign, `x :y` right (returns y)"),
+        example: "a:1 2 3\n{:x+1;2}[3]   / 4",
+    },
+    Builtin {
+        name: "+",
+        kind: BuiltinKind::Verb,
+        summary: "flip / add",
+        monadic: Some("`+x` flip, transpose a list of lists"),
+        dyadic: Some("`x+y` add"),
+        example: "+(\"ab\";\"cd\")   / (\"ac\";\"bd\")\n1 2+3          / 4 5",
+    },
+    Builtin {
+        name: "-",
+        kind: BuiltinKind::Verb,
+        summary: "negate / subtract",
+        monadic: Some("`-x` negate"),
+        dyadic: Some("`x-y` subtract"),
+        example: "- 1 2    / -1 -2\n1-2 3    / -1 -2",
+    },
+    Builtin {
+        name: "*",
+        kind: BuiltinKind::Verb,
+        summary: "first / multiply",
+        monadic: Some("`*x` first"),
+        dyadic: Some("`x*y` multiply"),
+        example: "*`a`b      / `a\n1 2*3 4    / 3 8",
+    },
+    Builtin {
+        name: "%",
+        kind: BuiltinKind::Verb,
+        summary: "sqrt / divide",
+        monadic: Some("`%x` square root"),
+        dyadic: Some("`x%y` divide"),
+        example: "%25      / 5.0\n2 3%4    / 0.5 0.75",
+    },
+    Builtin {
+        name: "!",
+        kind: BuiltinKind::Verb,
+        summary: "enum, keys / dict, mod",
+        monadic: Some("`!i` enumerate, `!I` odometer, `!d` keys"),
+        dyadic: Some("`x!y` make a dictionary, `i!I` mod (or div when i<0)"),
+        example: "!3           / 0 1 2\n`a`b!1 2     / `a`b!1 2\n10!1234 567  / 4 7",
+    },
+    Builtin {
+        name: "&",
+        kind: BuiltinKind::Verb,
+        summary: "where / min",
+        monadic: Some("`&I` where"),
+        dyadic: Some("`x&y` min / and"),
+        example: "&1 0 1 2    / 0 2 3 3\n2&-1 3      / -1 2",
+    },
+    Builtin {
+        name: "|",
+        kind: BuiltinKind::Verb,
+        summary: "reverse / max",
+        monadic: Some("`|x` reverse"),
+        dyadic: Some("`x|y` max / or"),
+        example: "|\"abc\"    / \"cba\"\n2|-1 3    / 2 3",
+    },
+    Builtin {
+        name: "<",
+        kind: BuiltinKind::Verb,
+        summary: "grade up / less",
+        monadic: Some("`<X` grade up (ascending indices), `<s` open a file"),
+        dyadic: Some("`x<y` less than"),
+        example: "<\"abacus\"   / 0 2 1 3 5 4\n0 2<1        / 1 0",
+    },
+    Builtin {
+        name: ">",
+        kind: BuiltinKind::Verb,
+        summary: "grade down / more",
+        monadic: Some("`>X` grade down (descending indices), `>i` close a handle"),
+        dyadic: Some("`x>y` greater than"),
+        example: ">\"abacus\"   / 4 5 3 1 0 2\n0 1>0 2      / 0 0",
+    },
+    Builtin {
+        name: "=",
+        kind: BuiltinKind::Verb,
+        summary: "group / equal",
+        monadic: Some("`=X` group, `=i` identity matrix"),
+        dyadic: Some("`x=y` equal"),
+        example: "=\"abba\"       / \"ab\"!(0 3;1 2)\n0 1 2=0 1 3   / 1 1 0",
+    },
+    Builtin {
+        name: "~",
+        kind: BuiltinKind::Verb,
+        summary: "not / match",
+        monadic: Some("`~x` not"),
+        dyadic: Some("`x~y` match"),
+        example: "~0 2     / 1 0\n2 3~2 3  / 1",
+    },
+    Builtin {
+        name: ",",
+        kind: BuiltinKind::Verb,
+        summary: "enlist / concat",
+        monadic: Some("`,x` enlist"),
+        dyadic: Some("`x,y` concatenate"),
+        example: ",0       / ,0\n0,1 2    / 0 1 2",
+    },
+    Builtin {
+        name: "^",
+        kind: BuiltinKind::Verb,
+        summary: "null / fill, without",
+        monadic: Some("`^x` null"),
+        dyadic: Some("`a^y` fill, `X^y` without"),
+        example: "^0 1 0N            / 0 0 1\n1^0 0N 2           / 0 1 2\n\"abracadabra\"^\"bc\" / \"araadara\"",
+    },
+    Builtin {
+        name: "#",
+        kind: BuiltinKind::Verb,
+        summary: "count / take, reshape",
+        monadic: Some("`#x` length, count"),
+        dyadic: Some("`i#y` take/reshape, `f#y` replicate, `x#d` take keys"),
+        example: "#\"abc\"   / 3\n3#2      / 2 2 2",
+    },
+    Builtin {
+        name: "_",
+        kind: BuiltinKind::Verb,
+        summary: "floor / drop, cut",
+        monadic: Some("`_n` floor, `_c` lowercase"),
+        dyadic: Some("`i_Y` drop, `I_Y` cut, `f_Y` weed out, `X_i` delete"),
+        example: "_12.34        / 12\n2_\"abcde\"     / \"cde\"",
+    },
+    Builtin {
+        name: "$",
+        kind: BuiltinKind::Verb,
+        summary: "string / pad, cast",
+        monadic: Some("`$x` string"),
+        dyadic: Some("`i$C` pad, `s$y` cast"),
+        example: "$12           / \"12\"\n5$\"abc\"       / \"abc  \"\n`c$97         / \"a\"",
+    },
+    Builtin {
+        name: "?",
+        kind: BuiltinKind::Verb,
+        summary: "distinct / find, roll",
+        monadic: Some("`?X` distinct, `?i` uniform random"),
+        dyadic: Some("`X?y` find, `i?x` roll, `-i?x` deal"),
+        example: "?\"abacus\"         / \"abcus\"\n\"abcde\"?\"bfe\"     / 1 0N 4",
+    },
+    Builtin {
+        name: "@",
+        kind: BuiltinKind::Verb,
+        summary: "type / apply at",
+        monadic: Some("`@x` type"),
+        dyadic: Some("`x@y` apply at depth 1, index"),
+        example: "@1            / `i\n{x+1}@2       / 3\n\"abc\"@1       / \"b\"",
+    },
+    Builtin {
+        name: ".",
+        kind: BuiltinKind::Verb,
+        summary: "value, eval / apply",
+        monadic: Some("`.S` get, `.C` eval, `.d` values"),
+        dyadic: Some("`x.y` apply with a list of arguments"),
+        example: ".\"1+2\"          / 3\n{x*y+1}. 2 3    / 8",
+    },
+    Builtin {
+        name: "'",
+        kind: BuiltinKind::Adverb,
+        summary: "each",
+        monadic: Some("`f'x` apply f to each item of x"),
+        dyadic: Some("`x f'y` apply f to each pair of items"),
+        example: "#'(\"ab\";\"cde\")   / 2 3\n1 2+'3 4          / 4 6",
+    },
+    Builtin {
+        name: "/",
+        kind: BuiltinKind::Adverb,
+        summary: "over / fixpoint",
+        monadic: Some("`f/x` fold x with f, or iterate a monadic f to a fixpoint"),
+        dyadic: Some("`x f/y` fold y with f starting from x, or repeat f x times"),
+        example: "+/1 2 3     / 6\n3{x*2}/1    / 8",
+    },
+    Builtin {
+        name: "\\",
+        kind: BuiltinKind::Adverb,
+        summary: "scan",
+        monadic: Some("`f\\x` like over, keeping every intermediate result"),
+        dyadic: Some("`x f\\y` scan y with f starting from x"),
+        example: "+\\1 2 3     / 1 3 6",
+    },
+    Builtin {
+        name: "':",
+        kind: BuiltinKind::Adverb,
+        summary: "each prior",
+        monadic: Some("`f':x` apply f to each item and its predecessor"),
+        dyadic: Some("`x f':y` each prior, with x as the first predecessor"),
+        example: "-':1 4 9     / 1 3 5",
+    },
+    Builtin {
+        name: "/:",
+        kind: BuiltinKind::Adverb,
+        summary: "each right",
+        monadic: None,
+        dyadic: Some("`x f/:y` apply f to x and each item of y"),
+        example: "1 2,/:3 4    / (1 2 3;1 2 4)",
+    },
+    Builtin {
+        name: "\\:",
+        kind: BuiltinKind::Adverb,
+        summary: "each left",
+        monadic: None,
+        dyadic: Some("`x f\\:y` apply f to each item of x and y"),
+        example: "1 2,\\:3 4    / (1 3 4;2 3 4)",
+    },
+    Builtin {
+        name: "0:",
+        kind: BuiltinKind::System,
+        summary: "read / write text lines",
+        monadic: Some("`0:s` read the lines of a file"),
+        dyadic: Some("`s 0:C` write lines to a file, `` `0:C`` prints to stdout"),
+        example: "`0:(\"hello\";\"world\")\nlines:0:\"data.txt\"",
+    },
+    Builtin {
+        name: "1:",
+        kind: BuiltinKind::System,
+        summary: "read / write bytes",
+        monadic: Some("`1:s` read a file as bytes"),
+        dyadic: Some("`s 1:C` write bytes to a file"),
+        example: "bytes:1:\"data.bin\"\n\"out.bin\"1:bytes",
+    },
+    Builtin {
+        name: "\\t",
+        kind: BuiltinKind::System,
+        summary: "time an expression",
+        monadic: Some("`\\t expr` milliseconds taken to evaluate expr"),
+        dyadic: Some("`\\t:n expr` time n repetitions"),
+        example: "\\t +/!1000000\n\\t:100 +/!1000",
+    },
+    Builtin {
+        name: "\\l",
+        kind: BuiltinKind::System,
+        summary: "load a script",
+        monadic: Some("`\\l file.k` evaluate the script at the given path"),
+        dyadic: None,
+        example: "\\l util.k",
+    },
+    Builtin {
+        name: "in",
+        kind: BuiltinKind::Function,
+        summary: "membership",
+        monadic: None,
+        dyadic: Some("`x in y` 1 where items of x occur in y"),
+        example: "2 5 in 1 2 3   / 1 0",
+    },
+    Builtin {
+        name: "bin",
+        kind: BuiltinKind::Function,
+        summary: "binary search",
+        monadic: None,
+        dyadic: Some("`x bin y` index of the last item of sorted x that is <= y"),
+        example: "1 3 5 bin 4   / 1",
+    },
+    Builtin {
+        name: "find",
+        kind: BuiltinKind::Function,
+        summary: "find",
+        monadic: None,
+        dyadic: Some("`x find y` index of the first occurrence of y in x"),
+        example: "\"abcde\" find \"c\"   / 2",
+    },
+    Builtin {
+        name: "til",
+        kind: BuiltinKind::Function,
+        summary: "range (`!i`)",
+        monadic: Some("`til i` the integers 0 to i-1"),
+        dyadic: None,
+        example: "til 4   / 0 1 2 3",
+    },
+    Builtin {
+        name: "where",
+        kind: BuiltinKind::Function,
+        summary: "indices of ones (`&I`)",
+        monadic: Some("`where I` each index repeated by its count"),
+        dyadic: None,
+        example: "where 1 0 1 2   / 0 2 3 3",
+    },
+    Builtin {
+        name: "count",
+        kind: BuiltinKind::Function,
+        summary: "length (`#x`)",
+        monadic: Some("`count x` number of items in x"),
+        dyadic: None,
+        example: "count \"abc\"   / 3",
+    },
+    Builtin {
+        name: "first",
+        kind: BuiltinKind::Function,
+        summary: "first item (`*x`)",
+        monadic: Some("`first x` the first item of x"),
+        dyadic: None,
+        example: "first 3 4 5   / 3",
+    },
+    Builtin {
+        name: "flip",
+        kind: BuiltinKind::Function,
+        summary: "transpose (`+x`)",
+        monadic: Some("`flip x` transpose a list of lists or a dictionary of columns"),
+        dyadic: None,
+        example: "flip (1 2;3 4)   / (1 3;2 4)",
+    },
+    Builtin {
+        name: "key",
+        kind: BuiltinKind::Function,
+        summary: "keys (`!d`)",
+        monadic: Some("`key d` the keys of a dictionary"),
+        dyadic: None,
+        example: "key `a`b!1 2   / `a`b",
+    },
+    Builtin {
+        name: "value",
+        kind: BuiltinKind::Function,
+        summary: "values, eval (`.x`)",
+        monadic: Some("`value d` the values of a dictionary, `value s` evaluate a string"),
+        dyadic: None,
+        example: "value `a`b!1 2   / 1 2\nvalue \"1+2\"     / 3",
+    },
+    Builtin {
+        name: "sum",
+        kind: BuiltinKind::Function,
+        summary: "total (`+/x`)",
+        monadic: Some("`sum x` sum of the items of x"),
+        dyadic: None,
+        example: "sum 1 2 3   / 6",
+    },
+    Builtin {
+        name: "sums",
+        kind: BuiltinKind::Function,
+        summary: "running totals (`+\\x`)",
+        monadic: Some("`sums x` cumulative sums of x"),
+        dyadic: None,
+        example: "sums 1 2 3   / 1 3 6",
+    },
+    Builtin {
+        name: "prd",
+        kind: BuiltinKind::Function,
+        summary: "product (`*/x`)",
+        monadic: Some("`prd x` product of the items of x"),
+        dyadic: None,
+        example: "prd 2 3 4   / 24",
+    },
+    Builtin {
+        name: "prds",
+        kind: BuiltinKind::Function,
+        summary: "running products (`*\\x`)",
+        monadic: Some("`prds x` cumulative products of x"),
+        dyadic: None,
+        example: "prds 2 3 4   / 2 6 24",
+    },
+    Builtin {
+        name: "max",
+        kind: BuiltinKind::Function,
+        summary: "maximum (`|/x`)",
+        monadic: Some("`max x` largest item of x"),
+        dyadic: None,
+        example: "max 3 1 4   / 4",
+    },
+    Builtin {
+        name: "maxs",
+        kind: BuiltinKind::Function,
+        summary: "running maximums (`|\\x`)",
+        monadic: Some("`maxs x` cumulative maximums of x"),
+        dyadic: None,
+        example: "maxs 1 3 2   / 1 3 3",
+    },
+    Builtin {
+        name: "min",
+        kind: BuiltinKind::Function,
+        summary: "minimum (`&/x`)",
+        monadic: Some("`min x` smallest item of x"),
+        dyadic: None,
+        example: "min 3 1 4   / 1",
+    },
+    Builtin {
+        name: "mins",
+        kind: BuiltinKind::Function,
+        summary: "running minimums (`&\\x`)",
+        monadic: Some("`mins x` cumulative minimums of x"),
+        dyadic: None,
+        example: "mins 3 1 2   / 3 1 1",
+    },
+    Builtin {
+        name: "avg",
+        kind: BuiltinKind::Function,
+        summary: "mean",
+        monadic: Some("`avg x` arithmetic mean of x"),
+        dyadic: None,
+        example: "avg 1 2 3   / 2f",
+    },
+    Builtin {
+        name: "avgs",
+        kind: BuiltinKind::Function,
+        summary: "running means",
+        monadic: Some("`avgs x` cumulative means of x"),
+        dyadic: None,
+        example: "avgs 1 2 3   / 1 1.5 2",
+    },
+    Builtin {
+        name: "deltas",
+        kind: BuiltinKind::Function,
+        summary: "differences",
+        monadic: Some("`deltas x` each item minus the one before it"),
+        dyadic: None,
+        example: "deltas 1 3 6   / 1 2 3",
+    },
+    Builtin {
+        name: "all",
+        kind: BuiltinKind::Function,
+        summary: "all true (`&/x`)",
+        monadic: Some("`all x` 1 if every item of x is nonzero"),
+        dyadic: None,
+        example: "all 1 1 0   / 0",
+    },
+    Builtin {
+        name: "any",
+        kind: BuiltinKind::Function,
+        summary: "any true (`|/x`)",
+        monadic: Some("`any x` 1 if some item of x is nonzero"),
+        dyadic: None,
+        example: "any 0 0 1   / 1",
+    },
+    Builtin {
+        name: "and",
+        kind: BuiltinKind::Function,
+        summary: "minimum (`x&y`)",
+        monadic: None,
+        dyadic: Some("`x and y` the lesser of x and y"),
+        example: "1 0 1 and 1 1 0   / 1 0 0",
+    },
+    Builtin {
+        name: "or",
+        kind: BuiltinKind::Function,
+        summary: "maximum (`x|y`)",
+        monadic: None,
+        dyadic: Some("`x or y` the greater of x and y"),
+        example: "1 0 1 or 0 0 1   / 1 0 1",
+    },
+    Builtin {
+        name: "not",
+        kind: BuiltinKind::Function,
+        summary: "logical not (`~x`)",
+        monadic: Some("`not x` 1 where x is zero"),
+        dyadic: None,
+        example: "not 0 1 2   / 1 0 0",
+    },
+    Builtin {
+        name: "neg",
+        kind: BuiltinKind::Function,
+        summary: "negate (`-x`)",
+        monadic: Some("`neg x` negate"),
+        dyadic: None,
+        example: "neg 1 -2   / -1 2",
+    },
+    Builtin {
+        name: "abs",
+        kind: BuiltinKind::Function,
+        summary: "absolute value",
+        monadic: Some("`abs x` magnitude of x"),
+        dyadic: None,
+        example: "abs -2 3   / 2 3",
+    },
+    Builtin {
+        name: "floor",
+        kind: BuiltinKind::Function,
+        summary: "round down (`_x`)",
+        monadic: Some("`floor x` largest integer not above x"),
+        dyadic: None,
+        example: "floor 2.5 -2.5   / 2 -3",
+    },
+    Builtin {
+        name: "ceiling",
+        kind: BuiltinKind::Function,
+        summary: "round up",
+        monadic: Some("`ceiling x` smallest integer not below x"),
+        dyadic: None,
+        example: "ceiling 2.5 -2.5   / 3 -2",
+    },
+    Builtin {
+        name: "sqrt",
+        kind: BuiltinKind::Function,
+        summary: "square root",
+        monadic: Some("`sqrt x` square root"),
+        dyadic: None,
+        example: "sqrt 4 9   / 2 3f",
+    },
+    Builtin {
+        name: "exp",
+        kind: BuiltinKind::Function,
+        summary: "exponential",
+        monadic: Some("`exp x` e to the power x"),
+        dyadic: None,
+        example: "exp 0   / 1f",
+    },
+    Builtin {
+        name: "log",
+        kind: BuiltinKind::Function,
+        summary: "natural logarithm",
+        monadic: Some("`log x` natural logarithm of x"),
+        dyadic: None,
+        example: "log 1   / 0f",
+    },
+    Builtin {
+        name: "div",
+        kind: BuiltinKind::Function,
+        summary: "integer division",
+        monadic: None,
+        dyadic: Some("`x div y` x divided by y, rounded down"),
+        example: "7 div 2   / 3",
+    },
+    Builtin {
+        name: "mod",
+        kind: BuiltinKind::Function,
+        summary: "modulus",
+        monadic: None,
+        dyadic: Some("`x mod y` remainder of x divided by y"),
+        example: "7 mod 3   / 1",
+    },
+    Builtin {
+        name: "reverse",
+        kind: BuiltinKind::Function,
+        summary: "reverse (`|x`)",
+        monadic: Some("`reverse x` the items of x in reverse order"),
+        dyadic: None,
+        example: "reverse 1 2 3   / 3 2 1",
+    },
+    Builtin {
+        name: "asc",
+        kind: BuiltinKind::Function,
+        summary: "sort ascending",
+        monadic: Some("`asc x` the items of x in ascending order"),
+        dyadic: None,
+        example: "asc 3 1 2   / 1 2 3",
+    },
+    Builtin {
+        name: "desc",
+        kind: BuiltinKind::Function,
+        summary: "sort descending",
+        monadic: Some("`desc x` the items of x in descending order"),
+        dyadic: None,
+        example: "desc 3 1 2   / 3 2 1",
+    },
+    Builtin {
+        name: "iasc",
+        kind: BuiltinKind::Function,
+        summary: "grade up (`<x`)",
+        monadic: Some("`iasc x` indices that sort x ascending"),
+        dyadic: None,
+        example: "iasc 3 1 2   / 1 2 0",
+    },
+    Builtin {
+        name: "idesc",
+        kind: BuiltinKind::Function,
+        summary: "grade down (`>x`)",
+        monadic: Some("`idesc x` indices that sort x descending"),
+        dyadic: None,
+        example: "idesc 3 1 2   / 0 2 1",
+    },
+    Builtin {
+        name: "distinct",
+        kind: BuiltinKind::Function,
+        summary: "unique items (`?x`)",
+        monadic: Some("`distinct x` the items of x without repeats"),
+        dyadic: None,
+        example: "distinct 1 1 2   / 1 2",
+    },
+    Builtin {
+        name: "group",
+        kind: BuiltinKind::Function,
+        summary: "group (`=x`)",
+        monadic: Some("`group x` dictionary from each distinct item to its indices"),
+        dyadic: None,
+        example: "group \"abab\"   / \"ab\"!(0 2;1 3)",
+    },
+    Builtin {
+        name: "last",
+        kind: BuiltinKind::Function,
+        summary: "last item",
+        monadic: Some("`last x` the last item of x"),
+        dyadic: None,
+        example: "last 1 2 3   / 3",
+    },
+    Builtin {
+        name: "next",
+        kind: BuiltinKind::Function,
+        summary: "next items",
+        monadic: Some("`next x` each item's successor, null at the end"),
+        dyadic: None,
+        example: "next 1 2 3   / 2 3 0N",
+    },
+    Builtin {
+        name: "prev",
+        kind: BuiltinKind::Function,
+        summary: "previous items",
+        monadic: Some("`prev x` each item's predecessor, null at the start"),
+        dyadic: None,
+        example: "prev 1 2 3   / 0N 1 2",
+    },
+    Builtin {
+        name: "enlist",
+        kind: BuiltinKind::Function,
+        summary: "list of one (`,x`)",
+        monadic: Some("`enlist x` a one-item list holding x"),
+        dyadic: None,
+        example: "enlist 1   / ,1",
+    },
+    Builtin {
+        name: "raze",
+        kind: BuiltinKind::Function,
+        summary: "flatten (`,/x`)",
+        monadic: Some("`raze x` join the items of x"),
+        dyadic: None,
+        example: "raze (1 2;3)   / 1 2 3",
+    },
+    Builtin {
+        name: "except",
+        kind: BuiltinKind::Function,
+        summary: "set difference",
+        monadic: None,
+        dyadic: Some("`x except y` the items of x not in y"),
+        example: "1 2 3 except 2   / 1 3",
+    },
+    Builtin {
+        name: "inter",
+        kind: BuiltinKind::Function,
+        summary: "intersection",
+        monadic: None,
+        dyadic: Some("`x inter y` the items of x that are also in y"),
+        example: "1 2 3 inter 2 3 4   / 2 3",
+    },
+    Builtin {
+        name: "union",
+        kind: BuiltinKind::Function,
+        summary: "union",
+        monadic: None,
+        dyadic: Some("`x union y` distinct items of x and y"),
+        example: "1 2 union 2 3   / 1 2 3",
+    },
+    Builtin {
+        name: "cross",
+        kind: BuiltinKind::Function,
+        summary: "cartesian product",
+        monadic: None,
+        dyadic: Some("`x cross y` every pairing of an item of x with an item of y"),
+        example: "1 2 cross 3 4   / (1 3;1 4;2 3;2 4)",
+    },
+    Builtin {
+        name: "within",
+        kind: BuiltinKind::Function,
+        summary: "range test",
+        monadic: None,
+        dyadic: Some("`x within y` 1 where x lies between the two items of y, inclusive"),
+        example: "2 5 within 1 3   / 1 0",
+    },
+    Builtin {
+        name: "like",
+        kind: BuiltinKind::Function,
+        summary: "pattern match",
+        monadic: None,
+        dyadic: Some("`x like y` 1 where string x matches the pattern y"),
+        example: "\"abc\" like \"a*\"   / 1",
+    },
+    Builtin {
+        name: "each",
+        kind: BuiltinKind::Function,
+        summary: "each (`f'x`)",
+        monadic: None,
+        dyadic: Some("`f each x` apply f to each item of x"),
+        example: "count each (1 2;3)   / 2 1",
+    },
+    Builtin {
+        name: "over",
+        kind: BuiltinKind::Function,
+        summary: "reduce (`f/x`)",
+        monadic: None,
+        dyadic: Some("`f over x` fold f across the items of x"),
+        example: "{x+y} over 1 2 3   / 6",
+    },
+    Builtin {
+        name: "scan",
+        kind: BuiltinKind::Function,
+        summary: "running reduce (`f\\x`)",
+        monadic: None,
+        dyadic: Some("`f scan x` every intermediate result of folding f over x"),
+        example: "{x+y} scan 1 2 3   / 1 3 6",
+    },
+    Builtin {
+        name: "sv",
+        kind: BuiltinKind::Function,
+        summary: "join / decode",
+        monadic: None,
+        dyadic: Some("`x sv y` join strings y with separator x, or decode digits y in base x"),
+        example: "\",\" sv (\"a\";\"b\")   / \"a,b\"",
+    },
+    Builtin {
+        name: "vs",
+        kind: BuiltinKind::Function,
+        summary: "split / encode",
+        monadic: None,
+        dyadic: Some("`x vs y` split string y on separator x, or encode y in base x"),
+        example: "\",\" vs \"a,b\"   / (\"a\";\"b\")",
+    },
+    Builtin {
+        name: "ss",
+        kind: BuiltinKind::Function,
+        summary: "string search",
+        monadic: None,
+        dyadic: Some("`x ss y` positions of substring y in x"),
+        example: "\"abcb\" ss \"b\"   / 1 3",
+    },
+    Builtin {
+        name: "ssr",
+        kind: BuiltinKind::Function,
+        summary: "string replace",
+        monadic: None,
+        dyadic: Some("`ssr[x;y;z]` replace each y in x with z"),
+        example: "ssr[\"abc\";\"b\";\"x\"]   / \"axc\"",
+    },
+    Builtin {
+        name: "lower",
+        kind: BuiltinKind::Function,
+        summary: "lowercase",
+        monadic: Some("`lower x` x in lowercase"),
+        dyadic: None,
+        example: "lower \"AbC\"   / \"abc\"",
+    },
+    Builtin {
+        name: "upper",
+        kind: BuiltinKind::Function,
+        summary: "uppercase",
+        monadic: Some("`upper x` x in uppercase"),
+        dyadic: None,
+        example: "upper \"AbC\"   / \"ABC\"",
+    },
+    Builtin {
+        name: "trim",
+        kind: BuiltinKind::Function,
+        summary: "strip spaces",
+        monadic: Some("`trim x` x without leading or trailing spaces"),
+        dyadic: None,
+        example: "trim \"  a \"   / ,\"a\"",
+    },
+    Builtin {
+        name: "ltrim",
+        kind: BuiltinKind::Function,
+        summary: "strip leading spaces",
+        monadic: Some("`ltrim x` x without leading spaces"),
+        dyadic: None,
+        example: "ltrim \"  a\"   / ,\"a\"",
+    },
+    Builtin {
+        name: "rtrim",
+        kind: BuiltinKind::Function,
+        summary: "strip trailing spaces",
+        monadic: Some("`rtrim x` x without trailing spaces"),
+        dyadic: None,
+        example: "rtrim \"a  \"   / ,\"a\"",
+    },
+    Builtin {
+        name: "string",
+        kind: BuiltinKind::Function,
+        summary: "format (`$x`)",
+        monadic: Some("`string x` x as a string"),
+        dyadic: None,
+        example: "string 12   / \"12\"",
+    },
+    Builtin {
+        name: "type",
+        kind: BuiltinKind::Function,
+        summary: "type code (`@x`)",
+        monadic: Some("`type x` the type code of x"),
+        dyadic: None,
+        example: "type 1 2   / 7h",
+    },
+    Builtin {
+        name: "cols",
+        kind: BuiltinKind::Function,
+        summary: "column names",
+        monadic: Some("`cols t` the column names of a table"),
+        dyadic: None,
+        example: "cols ([]a:1 2;b:3 4)   / `a`b",
+    },
+    Builtin {
+        name: "rand",
+        kind: BuiltinKind::Function,
+        summary: "random",
+        monadic: Some("`rand x` a random item of x, or a random number below x"),
+        dyadic: None,
+        example: "rand 10   / 7",
+    },
+    Builtin {
+        name: "get",
+        kind: BuiltinKind::Function,
+        summary: "read a value",
+        monadic: Some("`get x` the value of global x, or the contents of file x"),
+        dyadic: None,
+        example: "a:1\nget `a   / 1",
+    },
+    Builtin {
+        name: "set",
+        kind: BuiltinKind::Function,
+        summary: "assign globally",
+        monadic: None,
+        dyadic: Some("`x set y` assign y to the global or file named x"),
+        example: "`a set 1   / `a",
+    },
+    Builtin {
+        name: "read0",
+        kind: BuiltinKind::Function,
+        summary: "read text",
+        monadic: Some("`read0 f` the lines of text file f"),
+        dyadic: None,
+        example: "read0 `:data.txt",
+    },
+    Builtin {
+        name: "show",
+        kind: BuiltinKind::Function,
+        summary: "print",
+        monadic: Some("`show x` print x to the console"),
+        dyadic: None,
+        example: "show 1 2 3",
+    },
+    Builtin {
+        name: "system",
+        kind: BuiltinKind::Function,
+        summary: "system command",
+        monadic: Some("`system x` run the command string x"),
+        dyadic: None,
+        example: "system \"pwd\"",
+    },
+    Builtin {
+        name: "exit",
+        kind: BuiltinKind::Function,
+        summary: "exit",
+        monadic: Some("`exit x` terminate the process with status x"),
+        dyadic: None,
+        example: "exit 0",
+    },
+    Builtin {
+        name: ".z.d",
+        kind: BuiltinKind::System,
+        summary: "current date",
+        monadic: None,
+        dyadic: None,
+        example: ".z.d",
+    },
+    Builtin {
+        name: ".z.t",
+        kind: BuiltinKind::System,
+        summary: "current time",
+        monadic: None,
+        dyadic: None,
+        example: ".z.t",
+    },
+    Builtin {
+        name: ".z.p",
+        kind: BuiltinKind::System,
+        summary: "current timestamp",
+        monadic: None,
+        dyadic: None,
+        example: ".z.p",
+    },
+    Builtin {
+        name: ".z.f",
+        kind: BuiltinKind::System,
+        summary: "path of the running script",
+        monadic: None,
+        dyadic: None,
+        example: ".z.f",
+    },
+    Builtin {
+        name: ".z.x",
+        kind: BuiltinKind::System,
+        summary: "command-line arguments",
+        monadic: None,
+        dyadic: None,
+        example: "args:.z.x",
+    },
+    Builtin {
+        name: ".Q.a",
+        kind: BuiltinKind::System,
+        summary: "lowercase alphabet",
+        monadic: None,
+        dyadic: None,
+        example: ".Q.a   / \"abcdefghijklmnopqrstuvwxyz\"",
+    },
+    Builtin {
+        name: ".Q.A",
+        kind: BuiltinKind::System,
+        summary: "uppercase alphabet",
+        monadic: None,
+        dyadic: None,
+        example: ".Q.A   / \"ABCDEFGHIJKLMNOPQRSTUVWXYZ\"",
+    },
+    Builtin {
+        name: ".Q.n",
+        kind: BuiltinKind::System,
+        summary: "digits",
+        monadic: None,
+        dyadic: None,
+        example: ".Q.n   / \"0123456789\"",
+    },
+    Builtin {
+        name: "$[",
+        kind: BuiltinKind::Form,
+        summary: "conditional",
+        monadic: None,
+        dyadic: None,
+        example: "$[x>0;`pos;x<0;`neg;`zero]",
+    },
+    Builtin {
+        name: "@[",
+        kind: BuiltinKind::Form,
+        summary: "amend",
+        monadic: None,
+        dyadic: None,
+        example: "@[\"ABC\";1;_:]       / \"AbC\"\n@[2 3;0;+;4]        / 6 3",
+    },
+    Builtin {
+        name: ".[",
+        kind: BuiltinKind::Form,
+        summary: "drill amend, or trap errors",
+        monadic: None,
+        dyadic: None,
+        example: ".[+;1 2;\"E:\",]      / 3",
+    },
+    Builtin {
+        name: "?[",
+        kind: BuiltinKind::Form,
+        summary: "splice",
+        monadic: None,
+        dyadic: None,
+        example: "?[\"abcd\";1 3;\"xyz\"]   / \"axyzd\"",
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+pub struct Snippet {
+    pub label: &'static str,
+    pub detail: &'static str,
+    pub body: &'static str,
+}
+
+pub static SNIPPETS: &[Snippet] = &[
+    Snippet {
+        label: "lambda",
+        detail: "lambda with explicit parameters",
+        body: "{[${1:x};${2:y}] $0}",
+    },
+    Snippet {
+        label: "cond",
+        detail: "conditional",
+        body: "$[${1:cond};${2:then};${3:else}]",
+    },
+    Snippet {
+        label: "load",
+        detail: "load a script",
+        body: "\\l ${1:file}.k",
+    },
+    Snippet {
+        label: "each",
+        detail: "apply a lambda to each item",
+        body: "{${1:x}}'${2:list}",
+    },
+];
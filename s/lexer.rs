@@ -0,0 +1,200 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenKind {
+    Identifier,
+    Number,
+    String,
+    Symbol,
+    Comment,
+    Command,
+    Operator,
+    Punctuation,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub line: u32,
+    // Byte offset of the token within its line
+    pub start: usize,
+    pub text: &'a str,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_block_comment_open(line: &str) -> bool {
+    line.trim_end() == "/"
+}
+
+fn is_block_comment_close(line: &str) -> bool {
+    line.trim_end() == "\\"
+}
+
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut in_block_comment = false;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index as u32;
+        if in_block_comment {
+            if is_block_comment_close(line) {
+                in_block_comment = false;
+            }
+            if !line.is_empty() {
+                tokens.push(Token {
+                    kind: TokenKind::Comment,
+                    line: line_number,
+                    start: 0,
+                    text: line,
+                });
+            }
+            continue;
+        }
+        if is_block_comment_open(line) {
+            in_block_comment = true;
+            tokens.push(Token {
+                kind: TokenKind::Comment,
+                line: line_number,
+                start: 0,
+                text: line,
+            });
+            continue;
+        }
+        tokenize_line(line, line_number, &mut tokens);
+    }
+
+    tokens
+}
+
+fn string_end(line: &str, start: usize) -> usize {
+    let mut escaped = false;
+    for (idx, c) in line[start + 1..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return start + 1 + idx + 1,
+            _ => {}
+        }
+    }
+    line.len()
+}
+
+fn scan_while(line: &str, from: usize, predicate: impl Fn(char) -> bool) -> usize {
+    line[from..]
+        .find(|c: char| !predicate(c))
+        .map_or(line.len(), |idx| from + idx)
+}
+
+// Identifiers may be joined by dots into a namespaced name such as `.util.parse`
+fn name_end(line: &str, from: usize) -> usize {
+    let mut end = scan_while(line, from, is_identifier_char);
+    while line[end..].starts_with('.') && line[end + 1..].starts_with(char::is_alphabetic) {
+        end = scan_while(line, end + 1, is_identifier_char);
+    }
+    end
+}
+
+fn tokenize_line<'a>(line: &'a str, line_number: u32, tokens: &mut Vec<Token<'a>>) {
+    let mut push = |kind, start: usize, end: usize| {
+        tokens.push(Token {
+            kind,
+            line: line_number,
+            start,
+            text: &line[start..end],
+        })
+    };
+
+    if line.starts_with('\\') && line[1..].starts_with(|c: char| c.is_alphabetic()) {
+        push(TokenKind::Command, 0, line.len());
+        return;
+    }
+
+    let mut pos = 0;
+    while let Some(c) = line[pos..].chars().next() {
+        let start = pos;
+        let next = start + c.len_utf8();
+        pos = match c {
+            _ if c.is_whitespace() => next,
+            '/' if start == 0 || line[..start].ends_with(char::is_whitespace) => {
+                push(TokenKind::Comment, start, line.len());
+                return;
+            }
+            '"' => {
+                let end = string_end(line, start);
+                push(TokenKind::String, start, end);
+                end
+            }
+            '`' => {
+                let end = if line[next..].starts_with('"') {
+                    string_end(line, next)
+                } else {
+                    scan_while(line, next, |c| is_identifier_char(c) || c == '.' || c == ':')
+                };
+                push(TokenKind::Symbol, start, end);
+                end
+            }
+            _ if c.is_ascii_digit() => {
+                let end = scan_while(line, next, |c| c.is_alphanumeric() || c == '.');
+                push(TokenKind::Number, start, end);
+                end
+            }
+            '.' if line[next..].starts_with(char::is_alphabetic)
+                && !line[..start]
+                    .ends_with(|c: char| is_identifier_char(c) || ")]}".contains(c)) =>
+            {
+                let end = name_end(line, next);
+                push(TokenKind::Identifier, start, end);
+                end
+            }
+            _ if c.is_alphabetic() => {
+                let end = name_end(line, next);
+                push(TokenKind::Identifier, start, end);
+                end
+            }
+            '(' | ')' | '[' | ']' | '{' | '}' | ';' => {
+                push(TokenKind::Punctuation, start, next);
+                next
+            }
+            _ => {
+                let end = if matches!(c, '\'' | '/' | '\\') && line[next..].starts_with(':') {
+                    next + 1
+                } else {
+                    next
+                };
+                push(TokenKind::Operator, start, end);
+                end
+            }
+        };
+    }
+}
+
+// Byte offset where a comment begins on each line, if the line has one
+pub fn comment_starts(text: &str) -> Vec<Option<usize>> {
+    let mut starts = vec![None; text.lines().count()];
+    for token in tokenize(text) {
+        if token.kind == TokenKind::Comment {
+            starts[token.line as usize] = Some(token.start);
+        }
+    }
+    starts
+}
+
+// Byte ranges of string literals and comments on each line, which never contain identifiers
+pub fn opaque_spans(text: &str) -> Vec<Vec<std::ops::Range<usize>>> {
+    token_spans(text, &[TokenKind::Comment, TokenKind::String])
+}
+
+pub fn string_spans(text: &str) -> Vec<Vec<std::ops::Range<usize>>> {
+    token_spans(text, &[TokenKind::String])
+}
+
+fn token_spans(text: &str, kinds: &[TokenKind]) -> Vec<Vec<std::ops::Range<usize>>> {
+    let mut spans = vec![Vec::new(); text.lines().count()];
+    for token in tokenize(text) {
+        if kinds.contains(&token.kind) {
+            spans[token.line as usize].push(token.start..token.start + token.text.len());
+        }
+    }
+    spans
+}
@@ -1,281 +1,4532 @@
-use dashmap::DashMap;
+mod builtins;
+mod lexer;
+mod position;
+
+use dashmap::{DashMap, DashSet};
+use position::LineIndex;
 use regex::Regex;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::task::AbortHandle;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+const MAX_WORKSPACE_SYMBOLS: usize = 1000;
+
+const MAX_INDEXED_FILE_SIZE: u64 = 1 << 20;
+
+const MAX_INDEXED_FILES: usize = 5000;
+
+const RUN_LINE_COMMAND: &str = "klsp.runLine";
+const RUN_FILE_COMMAND: &str = "klsp.runFile";
+const EVAL_FILE_COMMAND: &str = "klsp.evalFile";
+const CHECK_INTERPRETER_COMMAND: &str = "klsp.checkInterpreter";
+
+const UNDEFINED_NAME_CODE: &str = "undefined-name";
+const UNUSED_DEFINITION_CODE: &str = "unused-definition";
+
+const SEMANTIC_TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::STRING,
+    SemanticTokenType::new("symbol"),
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::OPERATOR,
+];
+
+static DEFINITION_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?m)^[ \t]*(\.?[^\W\d]\w*(?:\.[^\W\d]\w*)*):[ \t]*(.*)").unwrap());
+static IDENTIFIER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\.?[^\W\d]\w*(?:\.[^\W\d]\w*)*$").unwrap());
+static SNIPPET_PLACEHOLDER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{\d+:([^}]*)\}|\$\d+").unwrap());
+// Lets the logLevel setting replace the RUST_LOG filter after startup
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DefKind {
+    Function,
+    Table,
+    Dictionary,
+    List,
+    Scalar,
+    // Any other expression, whose shape is only known at run time
+    Value,
+}
+
+impl DefKind {
+    fn symbol_kind(self) -> SymbolKind {
+        match self {
+            DefKind::Function => SymbolKind::FUNCTION,
+            DefKind::Table => SymbolKind::STRUCT,
+            DefKind::Dictionary => SymbolKind::OBJECT,
+            DefKind::List => SymbolKind::ARRAY,
+            DefKind::Scalar | DefKind::Value => SymbolKind::VARIABLE,
+        }
+    }
+
+    fn completion_kind(self) -> CompletionItemKind {
+        match self {
+            DefKind::Function => CompletionItemKind::FUNCTION,
+            DefKind::Table | DefKind::Dictionary => CompletionItemKind::STRUCT,
+            DefKind::List => CompletionItemKind::VALUE,
+            DefKind::Scalar | DefKind::Value => CompletionItemKind::VARIABLE,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Definition {
+    location: Location,
+    kind: DefKind,
+}
+
+// Definitions of a file read from disk and the files it loads in turn, as of its modification time
+#[derive(Clone)]
+struct LoadedFile {
+    modified: SystemTime,
+    definitions: HashMap<String, Vec<Definition>>,
+    loads: Vec<PathBuf>,
+}
+
+// Text of a tracked document and the version the client last sent, if it is open in the editor
+#[derive(Clone)]
+struct DocumentState {
+    text: String,
+    version: Option<i32>,
+    // Files named by its \l commands, kept with the text so the load graph is cheap to walk
+    loads: Vec<PathBuf>,
+}
+
+impl DocumentState {
+    fn new(uri: &Url, text: String, version: Option<i32>) -> Self {
+        let loads = loaded_paths(uri, &text);
+        DocumentState {
+            text,
+            version,
+            loads,
+        }
+    }
+}
+
+struct LambdaScope {
+    parameters: Vec<(String, Range)>,
+    // Names assigned directly in the body, which K makes local to the lambda
+    locals: Vec<(String, Range)>,
+    body: Range,
+    terminated: bool,
+}
+
+fn lambda_scopes(text: &str) -> Vec<LambdaScope> {
+    let tokens = lexer::tokenize(text);
+    let mut scopes = Vec::new();
+    let mut open: Vec<LambdaScope> = Vec::new();
+    let token_range = |token: &lexer::Token| Range {
+        start: Position::new(token.line, token.start as u32),
+        end: Position::new(token.line, (token.start + token.text.len()) as u32),
+    };
+
+    let mut index = 0;
+    while index < tokens.len() {
+        let token = &tokens[index];
+        match (token.kind, token.text) {
+            (lexer::TokenKind::Punctuation, "{") => {
+                let mut scope = LambdaScope {
+                    parameters: Vec::new(),
+                    locals: Vec::new(),
+                    body: token_range(token),
+                    terminated: false,
+                };
+                if tokens.get(index + 1).is_some_and(|next| next.text == "[") {
+                    index += 2;
+                    while let Some(parameter) = tokens.get(index).filter(|next| next.text != "]") {
+                        if parameter.kind == lexer::TokenKind::Identifier {
+                            scope
+                                .parameters
+                                .push((parameter.text.to_string(), token_range(parameter)));
+                        }
+                        index += 1;
+                    }
+                }
+                open.push(scope);
+            }
+            (lexer::TokenKind::Punctuation, "}") => {
+                if let Some(mut scope) = open.pop() {
+                    scope.body.end = token_range(token).end;
+                    scope.terminated = true;
+                    scopes.push(scope);
+                }
+            }
+            // Dotted names and `::` assign globals even inside a lambda
+            (lexer::TokenKind::Identifier, name)
+                if !name.contains('.')
+                    && tokens.get(index + 1).is_some_and(|next| next.text == ":")
+                    && tokens.get(index + 2).is_none_or(|after| after.text != ":") =>
+            {
+                if let Some(scope) = open.last_mut() {
+                    scope.locals.push((name.to_string(), token_range(token)));
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    // Unterminated lambdas extend to the end of the document
+    let end = Position::new(text.lines().count() as u32, 0);
+    scopes.extend(open.into_iter().map(|mut scope| {
+        scope.body.end = end;
+        scope
+    }));
+
+    scopes
+}
+
+impl LambdaScope {
+    // Explicit or implicit parameters and locals assigned in the body
+    fn declares(&self, name: &str) -> bool {
+        self.is_implicit(name)
+            || self
+                .parameters
+                .iter()
+                .chain(&self.locals)
+                .any(|(declared, _)| declared == name)
+    }
+
+    fn is_implicit(&self, name: &str) -> bool {
+        self.parameters.is_empty() && ["x", "y", "z"].contains(&name)
+    }
+}
+
+// Lambdas don't capture their surroundings, so only the innermost one can bind a name
+fn innermost_scope(scopes: &[LambdaScope], position: Position) -> Option<&LambdaScope> {
+    scopes
+        .iter()
+        .filter(|scope| scope.body.start <= position && position <= scope.body.end)
+        .max_by_key(|scope| scope.body.start)
+}
+
+// Occurrences bound by the same lambda as the name at position, or by none for a global
+fn binding_occurrences(text: &str, name: &str, position: Position) -> Vec<Range> {
+    let scopes = lambda_scopes(text);
+    let binding = |position: Position| {
+        innermost_scope(&scopes, position)
+            .filter(|scope| scope.declares(name))
+            .map(|scope| scope.body.start)
+    };
+    let target = binding(position);
+    find_occurrences(text, name)
+        .into_iter()
+        .filter(|range| binding(range.start) == target)
+        .collect()
+}
+
+// Where the lambda binding the name at position declares it, as parameter or local
+fn local_declarations(text: &str, name: &str, position: Position) -> Option<Vec<Range>> {
+    let scopes = lambda_scopes(text);
+    let scope = innermost_scope(&scopes, position).filter(|scope| scope.declares(name))?;
+    let declarations = scope.parameters.iter().chain(&scope.locals);
+    Some(
+        declarations
+            .filter(|(declared, _)| declared == name)
+            .map(|&(_, range)| range)
+            .collect(),
+    )
+}
 
-fn parse(text: &str, document_uri: &Url) -> HashMap<String, Location> {
-    let mut definitions = HashMap::new();
-    let re = Regex::new(r"(?m)^(\w+):\s*.*").unwrap();
+fn local_definition(text: &str, name: &str, position: Position) -> Option<Range> {
+    let scopes = lambda_scopes(text);
+    let scope = innermost_scope(&scopes, position)?;
+    if let Some((_, range)) = scope
+        .parameters
+        .iter()
+        .find(|(parameter, _)| parameter == name)
+    {
+        return Some(*range);
+    }
+    let locals: Vec<&Range> = scope
+        .locals
+        .iter()
+        .filter(|(local, _)| local == name)
+        .map(|(_, range)| range)
+        .collect();
+    if let Some(range) = locals
+        .iter()
+        .rev()
+        .find(|range| range.start.line <= position.line)
+        .or(locals.first())
+    {
+        return Some(**range);
+    }
+    // Implicit x, y and z are bound by the opening brace
+    let start = scope.body.start;
+    scope
+        .is_implicit(name)
+        .then(|| Range::new(start, Position::new(start.line, start.character + 1)))
+}
 
-    for cap in re.captures_iter(text) {
-        if let Some(var_name_match) = cap.get(1) {
-            let var_name = var_name_match.as_str();
+// Name, line, column of the name and the defining expression of every assignment
+fn definition_sites(text: &str) -> Vec<(&str, u32, u32, &str)> {
+    let comment_starts = lexer::comment_starts(text);
+    // Assignments inside a closed lambda are its locals, not top-level definitions
+    let locals: HashSet<(u32, u32)> = lambda_scopes(text)
+        .into_iter()
+        .filter(|scope| scope.terminated)
+        .flat_map(|scope| scope.locals.into_iter().map(|(_, range)| range.start))
+        .map(|start| (start.line, start.character))
+        .collect();
 
-            // Calculate the line number correctly
+    // Matches arrive in order, so lines are counted from the previous match rather than the start
+    let (mut counted_to, mut line_number, mut line_start) = (0, 0u32, 0);
+    DEFINITION_RE
+        .captures_iter(text)
+        .filter_map(|cap| {
+            let var_name_match = cap.get(1).unwrap();
             let byte_index = var_name_match.start();
-            let line_number = text[..byte_index].matches('\n').count() as u32;
-
-            let location = Location {
-                uri: document_uri.clone(),
-                range: Range {
-                    start: Position {
-                        line: line_number,
-                        character: 0,
-                    },
-                    end: Position {
-                        line: line_number,
-                        character: var_name.len() as u32,
-                    },
-                },
-            };
-            definitions.insert(var_name.to_string(), location);
+            for (idx, _) in text[counted_to..byte_index].match_indices('\n') {
+                line_number += 1;
+                line_start = counted_to + idx + 1;
+            }
+            counted_to = byte_index;
+            let column = byte_index - line_start;
+            if comment_starts[line_number as usize].is_some_and(|comment| comment <= column)
+                || locals.contains(&(line_number, column as u32))
+            {
+                return None;
+            }
+            Some((
+                var_name_match.as_str(),
+                line_number,
+                column as u32,
+                cap.get(2).unwrap().as_str(),
+            ))
+        })
+        .collect()
+}
+
+// Infers what an assignment binds from the first line of its right-hand side
+fn definition_kind(value: &str) -> DefKind {
+    let tokens: Vec<lexer::Token> = lexer::tokenize(value)
+        .into_iter()
+        .filter(|token| token.kind != lexer::TokenKind::Comment)
+        .collect();
+    let has_bang = tokens.iter().any(|token| token.text == "!");
+
+    match tokens.as_slice() {
+        [] => DefKind::Value,
+        [first, ..] if first.text == "{" => DefKind::Function,
+        // Flipped column dictionaries like +`a`b!(..), or [[]..] and ([]..) literals
+        [first, second, ..]
+            if first.text == "+" && second.kind == lexer::TokenKind::Symbol && has_bang =>
+        {
+            DefKind::Table
+        }
+        [first, second, ..] if matches!((first.text, second.text), ("[" | "(", "[")) => {
+            DefKind::Table
+        }
+        [first, ..] if first.kind == lexer::TokenKind::Symbol && has_bang => DefKind::Dictionary,
+        [first, ..] if first.text == "(" => DefKind::List,
+        _ => {
+            let mut atoms = 0;
+            for (index, token) in tokens.iter().enumerate() {
+                let negation = token.text == "-"
+                    && tokens.get(index + 1).is_some_and(|next| {
+                        next.kind == lexer::TokenKind::Number && next.start == token.start + 1
+                    });
+                match token.kind {
+                    _ if negation => {}
+                    lexer::TokenKind::Number | lexer::TokenKind::Symbol => atoms += 1,
+                    // A string is a list of characters
+                    lexer::TokenKind::String => atoms += 2,
+                    _ => return DefKind::Value,
+                }
+            }
+            if atoms == 1 {
+                DefKind::Scalar
+            } else {
+                DefKind::List
+            }
         }
     }
+}
+
+// Every assignment site of each name, in document order
+fn parse(text: &str, document_uri: &Url) -> HashMap<String, Vec<Definition>> {
+    let mut definitions: HashMap<String, Vec<Definition>> = HashMap::new();
+
+    for (var_name, line_number, column, value) in definition_sites(text) {
+        let location = Location {
+            uri: document_uri.clone(),
+            range: Range {
+                start: Position {
+                    line: line_number,
+                    character: column,
+                },
+                end: Position {
+                    line: line_number,
+                    character: column + var_name.len() as u32,
+                },
+            },
+        };
+        let kind = definition_kind(value);
+        definitions
+            .entry(var_name.to_string())
+            .or_default()
+            .push(Definition { location, kind });
+    }
 
     definitions
 }
 
-fn extract_variable_at_position(line: &str, char_position: u32) -> &str {
-    let is_variable_char = |c: char| c.is_alphanumeric() || c == '_';
-    let char_pos = char_position as usize;
-    let start = line[..char_pos]
-        .char_indices()
-        .rev()
-        .find(|&(_, c)| !is_variable_char(c))
-        .map_or(0, |(idx, _)| idx + 1);
+fn expression_end(text: &str, line: u32) -> Position {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut end = Position::new(line, 0);
 
-    let end = line[char_pos..]
-        .char_indices()
-        .find(|&(_, c)| !is_variable_char(c))
-        .map_or(line.len(), |(idx, _)| char_pos + idx);
+    for (line_index, line_text) in text.lines().enumerate().skip(line as usize) {
+        let mut escaped = false;
+        for c in line_text.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '{' | '[' | '(' if !in_string => depth += 1,
+                '}' | ']' | ')' if !in_string => depth -= 1,
+                _ => {}
+            }
+        }
+        end = Position::new(line_index as u32, line_text.len() as u32);
+        if depth <= 0 && !in_string {
+            break;
+        }
+    }
 
-    if start <= line.len() && end <= line.len() && start <= end {
-        &line[start..end]
-    } else {
-        ""
+    end
+}
+
+// First and last line of each top-level expression, skipping blank and comment-only lines
+fn expression_lines(text: &str) -> Vec<(u32, u32)> {
+    let comment_starts = lexer::comment_starts(text);
+    let lines: Vec<&str> = text.lines().collect();
+    let mut expressions = Vec::new();
+    let mut line = 0;
+
+    while line < lines.len() {
+        let code = &lines[line][..comment_starts[line].unwrap_or(lines[line].len())];
+        if code.trim().is_empty() {
+            line += 1;
+            continue;
+        }
+        let end = expression_end(text, line as u32).line;
+        expressions.push((line as u32, end));
+        line = end as usize + 1;
     }
+
+    expressions
 }
 
-struct KLanguageServer {
-    client: Client,
-    documents: DashMap<Url, String>,
-    definitions: DashMap<Url, HashMap<String, Location>>,
+// Scripts pulled in with `\l`, resolved against the directory of the loading document
+fn loaded_paths(document_uri: &Url, text: &str) -> Vec<PathBuf> {
+    let Some(base_dir) = document_uri
+        .to_file_path()
+        .ok()
+        .and_then(|path| path.parent().map(PathBuf::from))
+    else {
+        return vec![];
+    };
+
+    lexer::tokenize(text)
+        .into_iter()
+        .filter(|token| token.kind == lexer::TokenKind::Command)
+        .filter_map(|token| token.text.strip_prefix("\\l"))
+        .filter(|rest| rest.starts_with(char::is_whitespace))
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .map(|path| normalize_path(&base_dir.join(path)))
+        .collect()
 }
 
-impl KLanguageServer {
-    async fn diagnostics(&self, uri: Url) {
-        self.client
-            .publish_diagnostics(
-                uri.clone(),
-                get_diagnostics(
-                    &uri.to_file_path().unwrap(),
-                    self.documents
-                        .get(&uri)
-                        .unwrap()
-                        .split('\n')
-                        .map(|x| x.trim().to_owned())
-                        .collect(),
-                )
-                .await,
-                None,
-            )
-            .await;
+// Resolves `.` and `..` without touching the filesystem, so paths compare equal to document URIs
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
     }
+    normalized
 }
 
-#[tower_lsp::async_trait]
-impl LanguageServer for KLanguageServer {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
-        Ok(InitializeResult {
-            server_info: Some(ServerInfo {
-                name: "K Language Server".to_string(),
-                version: None,
-            }),
-            capabilities: ServerCapabilities {
-                text_document_sync: Some(
-                    TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)
-                ),
-                definition_provider: Some(OneOf::Left(true)),
-                rename_provider: Some(OneOf::Left(true)),
-                // hover_provider: Some(HoverProviderCapability::Simple(true)),
-                // completion_provider: Some(CompletionOptions::default()),
-                ..ServerCapabilities::default()
+fn undefined_name_diagnostics(text: &str, loaded_names: &HashSet<String>) -> Vec<Diagnostic> {
+    let tokens = lexer::tokenize(text);
+    let scopes = lambda_scopes(text);
+    let definitions: HashSet<&str> = definition_sites(text)
+        .into_iter()
+        .map(|(name, ..)| name)
+        .collect();
+    let is_assignment = |index: usize| {
+        tokens
+            .get(index + 1)
+            .is_some_and(|next| next.kind == lexer::TokenKind::Operator && next.text == ":")
+    };
+    // Names assigned anywhere, including locals inside lambdas
+    let assigned: HashSet<&str> = tokens
+        .iter()
+        .enumerate()
+        .filter(|&(index, token)| {
+            token.kind == lexer::TokenKind::Identifier && is_assignment(index)
+        })
+        .map(|(_, token)| token.text)
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        if token.kind != lexer::TokenKind::Identifier || is_assignment(index) {
+            continue;
+        }
+        let name = token.text;
+        // Single-letter namespaces such as `.z` belong to the interpreter
+        let system = name
+            .strip_prefix('.')
+            .and_then(|rest| rest.split('.').next())
+            .is_some_and(|namespace| namespace.len() == 1);
+        if system
+            || definitions.contains(name)
+            || assigned.contains(name)
+            || loaded_names.contains(name)
+            || builtins::lookup(name).is_some()
+        {
+            continue;
+        }
+        let range = Range {
+            start: Position::new(token.line, token.start as u32),
+            end: Position::new(token.line, (token.start + name.len()) as u32),
+        };
+        let innermost = scopes
+            .iter()
+            .filter(|scope| scope.body.start <= range.start && range.end <= scope.body.end)
+            .max_by_key(|scope| scope.body.start);
+        if innermost.is_some_and(|scope| {
+            scope
+                .parameters
+                .iter()
+                .any(|(parameter, _)| parameter == name)
+                || scope.is_implicit(name)
+        }) {
+            continue;
+        }
+        diagnostics.push(Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::WARNING),
+            code: Some(NumberOrString::String(UNDEFINED_NAME_CODE.to_string())),
+            source: Some("k-language-server".to_string()),
+            message: format!("Undefined name '{name}'"),
+            data: Some(LSPAny::from(name)),
+            ..Diagnostic::default()
+        });
+    }
+
+    diagnostics
+}
+
+// Names in used_elsewhere are referenced by files that load this one, so they count as used
+fn unused_definition_diagnostics(text: &str, used_elsewhere: &HashSet<String>) -> Vec<Diagnostic> {
+    let sites = definition_sites(text);
+    let is_site = |name: &str, start: Position| {
+        sites
+            .iter()
+            .any(|&(site, line, column, _)| site == name && Position::new(line, column) == start)
+    };
+
+    sites
+        .iter()
+        .filter(|&&(name, ..)| {
+            !used_elsewhere.contains(name)
+                && find_occurrences(text, name)
+                    .iter()
+                    .all(|occurrence| is_site(name, occurrence.start))
+        })
+        .map(|&(name, line, column, _)| Diagnostic {
+            range: Range {
+                start: Position::new(line, column),
+                end: Position::new(line, column + name.len() as u32),
             },
+            severity: Some(DiagnosticSeverity::HINT),
+            code: Some(NumberOrString::String(UNUSED_DEFINITION_CODE.to_string())),
+            source: Some("k-language-server".to_string()),
+            message: format!("'{name}' is never used"),
+            tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+            data: Some(LSPAny::from(name)),
+            ..Diagnostic::default()
         })
+        .collect()
+}
+
+fn define_stub_edit(text: &str, name: &str) -> Option<TextEdit> {
+    let first_use = find_occurrences(text, name).first()?.start.line;
+    let line = expression_lines(text)
+        .into_iter()
+        .find(|&(start, end)| start <= first_use && first_use <= end)
+        .map_or(first_use, |(start, _)| start);
+    Some(TextEdit {
+        range: Range::new(Position::new(line, 0), Position::new(line, 0)),
+        new_text: format!("{name}: 0N  / TODO\n"),
+    })
+}
+
+fn remove_definition_edit(text: &str, start: Position) -> Option<TextEdit> {
+    let (name, line, column, _) = definition_sites(text)
+        .into_iter()
+        .find(|&(_, line, column, _)| Position::new(line, column) == start)?;
+    let lines: Vec<&str> = text.lines().collect();
+    let end = expression_end(text, line);
+    let expression = lines[line as usize..=end.line as usize].join("\n");
+
+    // Writes through 0: or 1: must still run, so only the binding is dropped
+    let tokens = lexer::tokenize(&expression);
+    let writes = tokens.windows(2).any(|pair| {
+        let (handle, colon) = (&pair[0], &pair[1]);
+        handle.kind == lexer::TokenKind::Number
+            && matches!(handle.text, "0" | "1")
+            && colon.text == ":"
+            && colon.line == handle.line
+            && colon.start == handle.start + 1
+    });
+    if writes {
+        let line_text = lines[line as usize];
+        let value = line_text[column as usize + name.len() + 1..].trim_start();
+        return Some(TextEdit {
+            range: Range::new(
+                start,
+                Position::new(line, (line_text.len() - value.len()) as u32),
+            ),
+            new_text: String::new(),
+        });
     }
 
-    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
-        let document_uri = params.text_document_position.text_document.uri;
-        let position = params.text_document_position.position;
-        let new_name = params.new_name;
+    let range = if (end.line as usize) + 1 < lines.len() {
+        Range::new(Position::new(line, 0), Position::new(end.line + 1, 0))
+    } else if line > 0 {
+        Range::new(
+            Position::new(line - 1, lines[line as usize - 1].len() as u32),
+            end,
+        )
+    } else {
+        Range::new(Position::new(0, 0), end)
+    };
+    Some(TextEdit {
+        range,
+        new_text: String::new(),
+    })
+}
 
-        if let Some(doc_text) = self.documents.get(&document_uri) {
-            let definitions = parse(&doc_text, &document_uri);
-            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
-            let variable_name = extract_variable_at_position(line_text, position.character);
+// Collapses runs of whitespace in code while leaving strings and comments untouched
+fn format_line(line: &str, opaque_spans: &[std::ops::Range<usize>]) -> String {
+    let indent = line.len() - line.trim_start().len();
+    let mut formatted = line[..indent].to_string();
+    let mut position = indent;
 
-            let mut changes = HashMap::new();
-
-            if definitions.contains_key(variable_name) {
-                let mut edits = Vec::new();
-                for (line_index, line) in doc_text.lines().enumerate() {
-                    let mut start_char_index = 0;
-                    while let Some(found_pos) = line[start_char_index..].find(variable_name) {
-                        let start = start_char_index + found_pos;
-                        let end = start + variable_name.len();
-                        if (start == 0 || !line.chars().nth(start - 1).unwrap().is_alphanumeric())
-                            && (end == line.len()
-                                || !line.chars().nth(end).unwrap().is_alphanumeric())
-                        {
-                            let range = Range {
-                                start: Position::new(line_index as u32, start as u32),
-                                end: Position::new(line_index as u32, end as u32),
-                            };
-                            edits.push(TextEdit {
-                                range,
-                                new_text: new_name.clone(),
-                            });
-                        }
-                        start_char_index = end;
+    for span in opaque_spans.iter().chain(std::iter::once(&(line.len()..line.len()))) {
+        if span.end < position {
+            continue;
+        }
+        // Block comment lines are one span from column 0, so clip spans to the indent
+        let start = span.start.max(position);
+        for c in line[position..start].chars() {
+            if !c.is_whitespace() {
+                formatted.push(c);
+            } else if !formatted[indent..].ends_with(' ') {
+                formatted.push(' ');
+            }
+        }
+        formatted.push_str(&line[start..span.end]);
+        position = span.end;
+    }
+
+    formatted.trim_end().to_string()
+}
+
+// Formats the given lines of the text, aligning definitions only with their neighbours in range
+fn format_lines(text: &str, range: std::ops::Range<usize>) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let opaque_spans = lexer::opaque_spans(text);
+    let top_level: HashMap<u32, &str> = definition_sites(text)
+        .into_iter()
+        .filter(|&(_, _, column, _)| column == 0)
+        .map(|(name, line, _, _)| (line, name))
+        .collect();
+
+    let formatted: Vec<(String, Option<&str>)> = lines[range.clone()]
+        .iter()
+        .enumerate()
+        .map(|(offset, line)| {
+            let index = range.start + offset;
+            let formatted = format_line(line, &opaque_spans[index]);
+            match top_level.get(&(index as u32)) {
+                Some(&name) => {
+                    let value = formatted[name.len() + 1..].trim_start();
+                    if value.is_empty() || value.starts_with(':') {
+                        (formatted, None)
+                    } else {
+                        (value.to_string(), Some(name))
                     }
                 }
-                if !edits.is_empty() {
-                    changes.insert(document_uri, edits);
-                }
+                None => (formatted, None),
             }
+        })
+        .collect();
 
-            Ok(Some(WorkspaceEdit {
-                changes: Some(changes),
-                document_changes: None,
-                change_annotations: None,
-            }))
-        } else {
-            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+    // Consecutive definitions line up their values
+    let mut output = Vec::new();
+    for group in formatted.chunk_by(|a, b| a.1.is_some() && b.1.is_some()) {
+        let width = group
+            .iter()
+            .filter_map(|(_, name)| name.map(str::len))
+            .max();
+        for (line, name) in group {
+            match (name, width) {
+                (Some(name), Some(width)) => output.push(format!(
+                    "{name}:{}{line}",
+                    " ".repeat(width - name.len() + 1)
+                )),
+                _ => output.push(line.clone()),
+            }
         }
     }
 
-    async fn shutdown(&self) -> Result<()> {
-        Ok(())
+    output
+}
+
+fn format_text(text: &str) -> String {
+    let eol = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut lines = format_lines(text, 0..text.lines().count());
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
     }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let uri = params.text_document.uri;
-        let text = params.text_document.text;
-        self.documents.insert(uri.clone(), text.clone());
-        let definitions = parse(&text, &uri);
-        self.definitions.insert(uri.clone(), definitions);
-        self.diagnostics(uri).await;
+    lines.iter().map(|line| format!("{line}{eol}")).collect()
+}
+
+// Edits that turn the original text into the formatted text, one per changed line where possible
+// The \r of a CRLF ending is left alone, since clients treat it as part of the line break
+fn line_edits(original: &str, formatted: &str) -> Vec<TextEdit> {
+    let eol = if original.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    fn split(text: &str) -> Vec<&str> {
+        text.split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .collect()
     }
+    let original_lines = split(original);
+    let formatted_lines = split(formatted);
+    let prefix = original_lines
+        .iter()
+        .zip(&formatted_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = original_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(formatted_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let original_middle = &original_lines[prefix..original_lines.len() - suffix];
+    let formatted_middle = &formatted_lines[prefix..formatted_lines.len() - suffix];
+    let paired = original_middle.len().min(formatted_middle.len());
 
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        let uri = params.text_document.uri;
-        let text = &params.content_changes[0].text;
-        self.documents
-            .insert(uri.clone(), text.lines().map(str::to_owned).collect());
-        self.diagnostics(uri.clone()).await;
+    let mut edits: Vec<TextEdit> = original_middle[..paired]
+        .iter()
+        .zip(&formatted_middle[..paired])
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(offset, (original_line, formatted_line))| {
+            let line = (prefix + offset) as u32;
+            TextEdit {
+                range: Range::new(
+                    Position::new(line, 0),
+                    Position::new(line, original_line.len() as u32),
+                ),
+                new_text: formatted_line.to_string(),
+            }
+        })
+        .collect();
 
-        self.definitions.clear();
-        let definitions = parse(text, &uri);
-        self.definitions.insert(uri.clone(), definitions);
+    // Lines added or removed past the paired ones
+    if original_middle.len() != formatted_middle.len() {
+        let first = prefix + paired;
+        let last = prefix + original_middle.len();
+        let extra = &formatted_middle[paired..];
+        let edit = if last < original_lines.len() {
+            TextEdit {
+                range: Range::new(
+                    Position::new(first as u32, 0),
+                    Position::new(last as u32, 0),
+                ),
+                new_text: extra.iter().map(|line| format!("{line}{eol}")).collect(),
+            }
+        } else {
+            let end = Position::new(last as u32 - 1, original_lines[last - 1].len() as u32);
+            let start = match first.checked_sub(1) {
+                Some(previous) => {
+                    Position::new(previous as u32, original_lines[previous].len() as u32)
+                }
+                None => Position::new(0, 0),
+            };
+            let joined: String = extra.iter().map(|line| format!("{eol}{line}")).collect();
+            TextEdit {
+                range: Range::new(start, end),
+                new_text: if first == 0 {
+                    joined.strip_prefix(eol).unwrap_or(&joined).to_string()
+                } else {
+                    joined
+                },
+            }
+        };
+        edits.push(edit);
     }
 
-    async fn goto_definition(
-        &self,
-        params: GotoDefinitionParams,
-    ) -> Result<Option<GotoDefinitionResponse>> {
-        let document_uri = params.text_document_position_params.text_document.uri;
-        let position = params.text_document_position_params.position;
+    edits
+}
 
-        if let Some(doc_text) = self.documents.get(&document_uri) {
-            if let Some(definitions) = self.definitions.get(&document_uri) {
-                let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
-                let variable_name = extract_variable_at_position(line_text, position.character);
+// The last assignment at or above the line, or the first one when the use precedes them all
+fn nearest_definition(sites: &[Definition], line: u32) -> Option<&Definition> {
+    sites
+        .iter()
+        .rev()
+        .find(|site| site.location.range.start.line <= line)
+        .or(sites.first())
+}
 
-                let response =
-                    definitions.get(variable_name).map(|location| {
-                        GotoDefinitionResponse::Scalar(Location {
-                            uri: document_uri.clone(),
-                            range: location.range,
-                        })
-                    });
+fn semantic_tokens(
+    text: &str,
+    definitions: &HashMap<String, Vec<Definition>>,
+    range: Option<Range>,
+) -> Vec<SemanticToken> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut tokens = Vec::new();
+    let mut previous_line = 0;
+    let mut previous_start = 0;
 
-                Ok(response)
+    for token in lexer::tokenize(text) {
+        let token_type = match token.kind {
+            lexer::TokenKind::Identifier => match definitions.get(token.text) {
+                Some(sites) if sites.iter().any(|site| site.kind == DefKind::Function) => 1,
+                _ => 0,
+            },
+            lexer::TokenKind::Number => 2,
+            lexer::TokenKind::String => 3,
+            lexer::TokenKind::Symbol => 4,
+            lexer::TokenKind::Comment => 5,
+            lexer::TokenKind::Command => 6,
+            lexer::TokenKind::Operator => 7,
+            lexer::TokenKind::Punctuation => continue,
+        };
+        let start = lines[token.line as usize][..token.start].encode_utf16().count() as u32;
+        if range.is_some_and(|range| {
+            let position = Position::new(token.line, start);
+            position < range.start || position >= range.end
+        }) {
+            continue;
+        }
+        let delta_line = token.line - previous_line;
+        tokens.push(SemanticToken {
+            delta_line,
+            delta_start: if delta_line == 0 {
+                start - previous_start
             } else {
-                Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::InternalError))
+                start
+            },
+            length: token.text.encode_utf16().count() as u32,
+            token_type,
+            token_modifiers_bitset: 0,
+        });
+        previous_line = token.line;
+        previous_start = start;
+    }
+
+    tokens
+}
+
+fn semantic_tokens_edits(previous: &[SemanticToken], current: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    let prefix = previous
+        .iter()
+        .zip(current)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = previous[prefix..]
+        .iter()
+        .rev()
+        .zip(current[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let deleted = previous.len() - prefix - suffix;
+    let inserted = &current[prefix..current.len() - suffix];
+    if deleted == 0 && inserted.is_empty() {
+        return vec![];
+    }
+
+    // Edit offsets count integers, and every token is five of them
+    vec![SemanticTokensEdit {
+        start: (prefix * 5) as u32,
+        delete_count: (deleted * 5) as u32,
+        data: Some(inserted.to_vec()),
+    }]
+}
+
+fn comment_folding_ranges(text: &str) -> Vec<FoldingRange> {
+    let comment_fold = |start_line: usize, end_line: usize| FoldingRange {
+        start_line: start_line as u32,
+        end_line: end_line as u32,
+        kind: Some(FoldingRangeKind::Comment),
+        ..FoldingRange::default()
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let mut ranges = Vec::new();
+
+    let mut line_index = 0;
+    while line_index < lines.len() {
+        let line = lines[line_index];
+        if line.trim_end() == "/" {
+            let end_line = lines[line_index + 1..]
+                .iter()
+                .position(|line| line.trim_end() == "\\")
+                .map_or(lines.len() - 1, |offset| line_index + 1 + offset);
+            if end_line > line_index {
+                ranges.push(comment_fold(line_index, end_line));
+            }
+            line_index = end_line + 1;
+        } else if line.starts_with('/') {
+            let run = lines[line_index..]
+                .iter()
+                .take_while(|line| line.starts_with('/') && line.trim_end() != "/")
+                .count();
+            if run >= 3 {
+                ranges.push(comment_fold(line_index, line_index + run - 1));
             }
+            line_index += run;
         } else {
-            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+            line_index += 1;
         }
     }
+
+    ranges
 }
 
-async fn get_diagnostics(s: &PathBuf, doc_lines: Vec<String>) -> Vec<Diagnostic> {
-    let output = tokio::process::Command::new("/usr/local/bin/k")
-        .arg(s)
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .expect("failed to execute process")
-        .wait_with_output()
-        .await
-        .expect("failed to wait on child");
+fn folding_ranges(text: &str) -> Vec<FoldingRange> {
+    let mut ranges = comment_folding_ranges(text);
+    let mut open_lines = Vec::new();
 
-    if !output.status.success() {
-        parse_diagnostics_from_stderr(
-            String::from_utf8_lossy(&output.stderr).to_string(),
-            &doc_lines,
-        )
-    } else {
-        vec![] // Return an empty vector if the process fails
+    for token in lexer::tokenize(text) {
+        if token.kind != lexer::TokenKind::Punctuation {
+            continue;
+        }
+        match token.text {
+            "{" | "[" | "(" => open_lines.push(token.line),
+            "}" | "]" | ")" => {
+                if let Some(start_line) = open_lines.pop().filter(|&line| line < token.line) {
+                    ranges.push(FoldingRange {
+                        start_line,
+                        end_line: token.line,
+                        kind: Some(FoldingRangeKind::Region),
+                        ..FoldingRange::default()
+                    });
+                }
+            }
+            _ => {}
+        }
     }
+
+    ranges
 }
 
-fn parse_diagnostics_from_stderr(stderr_output: String, doc_lines: &[String]) -> Vec<Diagnostic> {
-    dbg!(&stderr_output);
-    let mut diagnostics = Vec::new();
-    let stderr_lines = stderr_output.lines();
-    let error_message = format!("Syntax error at: {stderr_output}");
-    let mut character = 0;
-    let mut line_number = 0;
+fn bracket_spans(text: &str) -> Vec<Range> {
+    let mut spans = Vec::new();
+    let mut open = Vec::new();
 
-    for line in stderr_lines {
-        if line.trim().starts_with('^') {
-            character = line.find('^').unwrap_or(0) as u64;
-        } else if !line.trim().starts_with("'parse") {
-            dbg!(&doc_lines);
-            line_number = doc_lines
-                .iter()
-                .position(|r| r.trim() == line.trim())
-                .unwrap_or(0);
+    for token in lexer::tokenize(text) {
+        if token.kind != lexer::TokenKind::Punctuation {
+            continue;
+        }
+        match token.text {
+            "{" | "[" | "(" => open.push(Position::new(token.line, token.start as u32)),
+            "}" | "]" | ")" => {
+                if let Some(start) = open.pop() {
+                    spans.push(Range {
+                        start,
+                        end: Position::new(token.line, token.start as u32 + 1),
+                    });
+                }
+            }
+            _ => {}
         }
     }
-    let diagnostic =
-        Diagnostic::new(
-            Range::new(
-                Position::new(line_number as u32, character as u32),
-                Position::new(line_number as u32, character as u32 + 1),
-            ),
-            Some(DiagnosticSeverity::ERROR),
-            None,
-            Some("k-language-server".to_string()),
-            error_message.clone(),
-            None,
-            None,
-        );
-    diagnostics.push(diagnostic);
-    diagnostics
+
+    spans
 }
 
-#[tokio::main]
-async fn main() {
-    let (service, socket) = LspService::new(|client| KLanguageServer {
-        client,
-        documents: DashMap::new(),
-        definitions: DashMap::new(),
+// Innermost bracket still open at the position, with where it was opened
+fn open_bracket_at(text: &str, position: Position) -> Option<(&str, Position)> {
+    let mut open = Vec::new();
+
+    for token in lexer::tokenize(text) {
+        let start = Position::new(token.line, token.start as u32);
+        if start >= position {
+            break;
+        }
+        if token.kind != lexer::TokenKind::Punctuation {
+            continue;
+        }
+        match token.text {
+            "{" | "[" | "(" => open.push((token.text, start)),
+            "}" | "]" | ")" => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    open.pop()
+}
+
+// Whether text typed at the position would be code rather than part of a string or comment
+fn is_code_at(text: &str, position: Position) -> bool {
+    let offset = byte_offset_at(text, position);
+    let column = offset - byte_offset_at(text, Position::new(position.line, 0));
+    let mut probe = text.to_string();
+    probe.insert(offset, 'x');
+
+    !lexer::tokenize(&probe).iter().any(|token| {
+        token.line == position.line
+            && matches!(token.kind, lexer::TokenKind::Comment | lexer::TokenKind::String)
+            && (token.start..token.start + token.text.len()).contains(&column)
+    })
+}
+
+fn semicolon_edit(text: &str, position: Position) -> Option<TextEdit> {
+    let typed = Position::new(position.line, position.character.checked_sub(1)?);
+    if !is_code_at(text, typed) || open_bracket_at(text, typed)?.0 != "[" {
+        return None;
+    }
+    if text[byte_offset_at(text, position)..].starts_with(|c: char| c.is_whitespace() || c == ']') {
+        return None;
+    }
+
+    Some(TextEdit {
+        range: Range::new(position, position),
+        new_text: " ".to_string(),
+    })
+}
+
+fn newline_indent_edit(
+    text: &str,
+    position: Position,
+    options: &FormattingOptions,
+) -> Option<TextEdit> {
+    if !is_code_at(text, position) {
+        return None;
+    }
+    let (_, open) = open_bracket_at(text, position)?;
+    let unit = if options.insert_spaces {
+        " ".repeat(options.tab_size as usize)
+    } else {
+        "\t".to_string()
+    };
+    // Indent by the characters before the bracket, since its byte column overcounts multibyte text
+    let open_line = text.lines().nth(open.line as usize).unwrap_or("");
+    let column = open_line[..open.character as usize].chars().count();
+    let indent = " ".repeat(column) + &unit;
+    let line_text = text.lines().nth(position.line as usize).unwrap_or("");
+    let current = line_text.len() - line_text.trim_start().len();
+    if line_text[..current] == indent {
+        return None;
+    }
+
+    Some(TextEdit {
+        range: Range::new(
+            Position::new(position.line, 0),
+            Position::new(position.line, current as u32),
+        ),
+        new_text: indent,
+    })
+}
+
+fn selection_range_at(text: &str, position: Position) -> SelectionRange {
+    let contains = |outer: &Range, inner: &Range| outer.start <= inner.start && inner.end <= outer.end;
+    let line_text = text.lines().nth(position.line as usize).unwrap_or("");
+    let mut candidates = Vec::new();
+
+    let (start, end) = variable_range_at_position(line_text, position.character);
+    if start < end {
+        candidates.push(Range {
+            start: Position::new(position.line, start as u32),
+            end: Position::new(position.line, end as u32),
+        });
+    }
+    let cursor = Range {
+        start: position,
+        end: position,
+    };
+    let spans: Vec<Range> = bracket_spans(text)
+        .into_iter()
+        .filter(|span| contains(span, &cursor))
+        .collect();
+    // A bracket opened or closed on this line that runs past it stretches the line to cover it
+    let line = Range {
+        start: Position::new(position.line, 0),
+        end: Position::new(position.line, line_text.len() as u32),
+    };
+    let mut line_range = line;
+    for span in spans.iter().filter(|span| !contains(span, &line)) {
+        line_range.start = line_range.start.min(Position::new(span.start.line, 0));
+        line_range.end = line_range.end.max(span.end);
+    }
+    candidates.extend(spans);
+    candidates.push(line_range);
+    candidates.extend(
+        definition_sites(text)
+            .into_iter()
+            .map(|(_, line_number, column, _)| Range {
+                start: Position::new(line_number, column),
+                end: expression_end(text, line_number),
+            })
+            .filter(|definition| contains(definition, &cursor)),
+    );
+    // A range can only contain ranges that start no earlier and end no later, so this
+    // puts every candidate after the ones nested inside it
+    candidates.sort_by_key(|range| {
+        (
+            std::cmp::Reverse((range.start.line, range.start.character)),
+            (range.end.line, range.end.character),
+        )
     });
+
+    let mut chain: Vec<Range> = Vec::new();
+    for candidate in candidates {
+        if chain
+            .last()
+            .is_none_or(|last| contains(&candidate, last) && candidate != *last)
+        {
+            chain.push(candidate);
+        }
+    }
+
+    chain
+        .into_iter()
+        .rev()
+        .fold(None, |parent, range| {
+            Some(SelectionRange {
+                range,
+                parent: parent.map(Box::new),
+            })
+        })
+        .unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Valence {
+    Monadic,
+    Dyadic,
+    Ambiguous,
+}
+
+fn verb_valence(
+    tokens: &[lexer::Token],
+    verb_index: usize,
+    definitions: &HashMap<String, Vec<Definition>>,
+) -> Valence {
+    let Some(left) = verb_index.checked_sub(1).map(|index| &tokens[index]) else {
+        return Valence::Monadic;
+    };
+    match left.kind {
+        lexer::TokenKind::Number | lexer::TokenKind::String | lexer::TokenKind::Symbol => {
+            Valence::Dyadic
+        }
+        lexer::TokenKind::Identifier => match definitions.get(left.text) {
+            Some(sites) if sites.iter().all(|site| site.kind != DefKind::Function) => {
+                Valence::Dyadic
+            }
+            // A function to the left may itself take the verb's result as its argument
+            _ => Valence::Ambiguous,
+        },
+        lexer::TokenKind::Punctuation if matches!(left.text, ")" | "]") => Valence::Dyadic,
+        lexer::TokenKind::Punctuation if left.text == "}" => Valence::Ambiguous,
+        _ => Valence::Monadic,
+    }
+}
+
+fn builtin_signatures(builtin: &builtins::Builtin, valence: Valence) -> Vec<SignatureInformation> {
+    let signature = |label: String, documentation: &str, parameters: &[&str]| SignatureInformation {
+        label,
+        documentation: Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: documentation.to_string(),
+        })),
+        parameters: Some(
+            parameters
+                .iter()
+                .map(|parameter| ParameterInformation {
+                    label: ParameterLabel::Simple(parameter.to_string()),
+                    documentation: None,
+                })
+                .collect(),
+        ),
+        active_parameter: None,
+    };
+
+    let mut signatures = Vec::new();
+    if valence != Valence::Dyadic {
+        if let Some(monadic) = builtin.monadic {
+            signatures.push(signature(format!("{} x", builtin.name), monadic, &["x"]));
+        }
+    }
+    if valence != Valence::Monadic {
+        if let Some(dyadic) = builtin.dyadic {
+            signatures.push(signature(format!("x {} y", builtin.name), dyadic, &["x", "y"]));
+        }
+    }
+    signatures
+}
+
+// Explicit parameters of the lambda defined at the position, or the implicit x, y and z it uses
+fn lambda_parameters(text: &str, definition: Position) -> Vec<String> {
+    let tokens = lexer::tokenize(text);
+    let Some(open) = tokens.iter().position(|token| {
+        Position::new(token.line, token.start as u32) > definition && token.text == "{"
+    }) else {
+        return vec![];
+    };
+
+    if tokens.get(open + 1).is_some_and(|next| next.text == "[") {
+        return tokens[open + 2..]
+            .iter()
+            .take_while(|token| token.text != "]")
+            .filter(|token| token.kind == lexer::TokenKind::Identifier)
+            .map(|token| token.text.to_string())
+            .collect();
+    }
+
+    let mut depth = 0;
+    let mut implicit_count = 0;
+    for token in &tokens[open..] {
+        match (token.kind, token.text) {
+            (lexer::TokenKind::Punctuation, "{") => depth += 1,
+            (lexer::TokenKind::Punctuation, "}") => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            (lexer::TokenKind::Identifier, name) if depth == 1 => {
+                if let Some(implicit) = ["x", "y", "z"].iter().position(|&p| p == name) {
+                    implicit_count = implicit_count.max(implicit + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    ["x", "y", "z"][..implicit_count]
+        .iter()
+        .map(|parameter| parameter.to_string())
+        .collect()
+}
+
+fn implicit_parameter_hints(text: &str) -> Vec<InlayHint> {
+    struct Lambda {
+        open: Position,
+        has_parameters: bool,
+        implicit_count: usize,
+    }
+
+    let tokens = lexer::tokenize(text);
+    let mut hints = Vec::new();
+    let mut open: Vec<Lambda> = Vec::new();
+
+    for (index, token) in tokens.iter().enumerate() {
+        match (token.kind, token.text) {
+            (lexer::TokenKind::Punctuation, "{") => open.push(Lambda {
+                open: Position::new(token.line, token.start as u32 + 1),
+                has_parameters: tokens.get(index + 1).is_some_and(|next| next.text == "["),
+                implicit_count: 0,
+            }),
+            (lexer::TokenKind::Punctuation, "}") => {
+                if let Some(lambda) = open.pop() {
+                    if !lambda.has_parameters && lambda.implicit_count > 0 {
+                        hints.push(InlayHint {
+                            position: lambda.open,
+                            label: InlayHintLabel::String(format!(
+                                "[{}]",
+                                ["x", "y", "z"][..lambda.implicit_count].join(";")
+                            )),
+                            kind: Some(InlayHintKind::PARAMETER),
+                            text_edits: None,
+                            tooltip: None,
+                            padding_left: None,
+                            padding_right: Some(true),
+                            data: None,
+                        });
+                    }
+                }
+            }
+            (lexer::TokenKind::Identifier, name) => {
+                if let Some(lambda) = open.last_mut() {
+                    if let Some(implicit) = ["x", "y", "z"].iter().position(|&p| p == name) {
+                        lambda.implicit_count = lambda.implicit_count.max(implicit + 1);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    hints
+}
+
+// Offset of a position whose column is already a byte offset into its line
+fn byte_offset_at(text: &str, position: Position) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(position.line as usize)
+        .map(str::len)
+        .sum();
+    let line = text[line_start..].split('\n').next().unwrap_or("");
+    let mut column = (position.character as usize).min(line.len());
+    while !line.is_char_boundary(column) {
+        column -= 1;
+    }
+    line_start + column
+}
+
+fn offset_at(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(idx) => line_start += idx + 1,
+            None => return text.len(),
+        }
+    }
+    let line_end = text[line_start..]
+        .find('\n')
+        .map_or(text.len(), |idx| line_start + idx);
+
+    let mut character = 0;
+    for (idx, c) in text[line_start..line_end].char_indices() {
+        if character >= position.character {
+            return line_start + idx;
+        }
+        character += c.len_utf16() as u32;
+    }
+    line_end
+}
+
+// The query's characters appear in the name in order, not necessarily adjacent
+fn fuzzy_match(name: &str, query: &str) -> bool {
+    let mut name_chars = name.chars();
+    query.chars().all(|c| name_chars.any(|n| n == c))
+}
+
+// Dots join the parts of a namespaced name such as `.util.parse`
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+// A trailing dot is the apply verb rather than part of the name
+fn continues_name(rest: &str) -> bool {
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some('.') => chars.next().is_some_and(|c| c.is_alphanumeric() || c == '_'),
+        Some(c) => c.is_alphanumeric() || c == '_',
+        None => false,
+    }
+}
+
+fn find_occurrences(text: &str, name: &str) -> Vec<Range> {
+    find_occurrences_outside(text, name, &lexer::opaque_spans(text))
+}
+
+// Whole-name matches that do not start inside any of the per-line spans
+fn find_occurrences_outside(
+    text: &str,
+    name: &str,
+    opaque_spans: &[Vec<std::ops::Range<usize>>],
+) -> Vec<Range> {
+    let mut occurrences = Vec::new();
+    if name.is_empty() {
+        return occurrences;
+    }
+
+    for (line_index, line) in text.lines().enumerate() {
+        let mut start_char_index = 0;
+        while let Some(found_pos) = line[start_char_index..].find(name) {
+            let start = start_char_index + found_pos;
+            let end = start + name.len();
+            if !opaque_spans[line_index].iter().any(|span| span.contains(&start))
+                && !line[..start].chars().next_back().is_some_and(is_name_char)
+                && !continues_name(&line[end..])
+            {
+                occurrences.push(Range {
+                    start: Position::new(line_index as u32, start as u32),
+                    end: Position::new(line_index as u32, end as u32),
+                });
+            }
+            start_char_index = end;
+        }
+    }
+
+    occurrences
+}
+
+fn variable_range_at_position(line: &str, char_position: u32) -> (usize, usize) {
+    // Clients may point past the end of the line or into the middle of a multi-byte char
+    let mut char_pos = (char_position as usize).min(line.len());
+    while !line.is_char_boundary(char_pos) {
+        char_pos -= 1;
+    }
+    let start = line[..char_pos]
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !is_name_char(c))
+        .map_or(0, |(idx, c)| idx + c.len_utf8());
+
+    let end = line[char_pos..]
+        .char_indices()
+        .find(|&(_, c)| !is_name_char(c))
+        .map_or(line.len(), |(idx, _)| char_pos + idx);
+
+    (start, start + line[start..end].trim_end_matches('.').len())
+}
+
+fn extract_variable_at_position(line: &str, char_position: u32) -> &str {
+    let (start, end) = variable_range_at_position(line, char_position);
+    line.get(start..end).unwrap_or("")
+}
+
+fn extract_token_at_position(line: &str, char_position: u32) -> (usize, &str) {
+    let char_pos = char_position as usize;
+    match line.get(char_pos..).and_then(|rest| rest.chars().next()) {
+        Some(c) if c.is_alphanumeric() || c == '_' => {
+            let (start, end) = variable_range_at_position(line, char_position);
+            (start, &line[start..end])
+        }
+        Some(c) if !c.is_whitespace() => (char_pos, &line[char_pos..char_pos + c.len_utf8()]),
+        _ => (char_pos, ""),
+    }
+}
+
+fn find_k_binary() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("KLSP_K_PATH") {
+        return Some(PathBuf::from(path));
+    }
+
+    let binary_name = format!("k{}", std::env::consts::EXE_SUFFIX);
+    std::env::var_os("PATH")
+        .and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(&binary_name))
+                .find(|candidate| candidate.is_file())
+        })
+        .or_else(|| Some(PathBuf::from("/usr/local/bin/k")).filter(|path| path.is_file()))
+}
+
+// A configured path may be mistyped, which would otherwise only show up when diagnostics fail
+fn interpreter_problem(k_binary_path: Option<&Path>) -> Option<String> {
+    match k_binary_path {
+        None => Some(
+            "Could not find the K interpreter; set kBinaryPath in initializationOptions \
+             or KLSP_K_PATH to enable diagnostics"
+                .to_string(),
+        ),
+        Some(path) if !path.is_file() => Some(format!(
+            "No K interpreter at {}; set kBinaryPath in initializationOptions or KLSP_K_PATH \
+             to enable diagnostics",
+            path.display()
+        )),
+        Some(_) => None,
+    }
+}
+
+fn strip_snippet_placeholders(snippet: &str) -> String {
+    SNIPPET_PLACEHOLDER_RE.replace_all(snippet, "$1").into_owned()
+}
+
+#[derive(Clone)]
+struct Config {
+    k_binary_path: Option<PathBuf>,
+    diagnostics_debounce: Duration,
+    diagnostics_timeout: Duration,
+    diagnostic_severity: DiagnosticSeverity,
+    implicit_parameter_hints: bool,
+    space_after_semicolon: bool,
+    formatter_path: Option<PathBuf>,
+    rename_in_comments: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            k_binary_path: find_k_binary(),
+            diagnostics_debounce: Duration::from_millis(300),
+            diagnostics_timeout: Duration::from_secs(5),
+            diagnostic_severity: DiagnosticSeverity::ERROR,
+            implicit_parameter_hints: true,
+            space_after_semicolon: false,
+            formatter_path: None,
+            rename_in_comments: false,
+        }
+    }
+}
+
+impl Config {
+    fn apply_settings(&mut self, options: Option<&LSPAny>) {
+        let option = |name: &str| options.and_then(|options| options.get(name));
+
+        if let Some(path) = option("kBinaryPath").and_then(|path| path.as_str()) {
+            self.k_binary_path = Some(PathBuf::from(path));
+        }
+        if let Some(debounce) = option("diagnosticsDebounceMs").and_then(|debounce| debounce.as_u64()) {
+            self.diagnostics_debounce = Duration::from_millis(debounce);
+        }
+        if let Some(timeout) = option("diagnosticsTimeoutMs").and_then(|timeout| timeout.as_u64()) {
+            self.diagnostics_timeout = Duration::from_millis(timeout);
+        }
+        let severity = option("diagnosticSeverity").and_then(|severity| severity.as_str());
+        if let Some(severity) = severity.and_then(|severity| match severity.to_lowercase().as_str() {
+            "error" => Some(DiagnosticSeverity::ERROR),
+            "warning" => Some(DiagnosticSeverity::WARNING),
+            "information" => Some(DiagnosticSeverity::INFORMATION),
+            "hint" => Some(DiagnosticSeverity::HINT),
+            _ => None,
+        }) {
+            self.diagnostic_severity = severity;
+        }
+        if let Some(enabled) = option("implicitParameterHints").and_then(|enabled| enabled.as_bool()) {
+            self.implicit_parameter_hints = enabled;
+        }
+        if let Some(path) = option("kFormatterPath").and_then(|path| path.as_str()) {
+            self.formatter_path = Some(PathBuf::from(path));
+        }
+        if let Some(enabled) = option("spaceAfterSemicolon").and_then(|enabled| enabled.as_bool()) {
+            self.space_after_semicolon = enabled;
+        }
+        if let Some(enabled) = option("renameInComments").and_then(|enabled| enabled.as_bool()) {
+            self.rename_in_comments = enabled;
+        }
+        // Overrides RUST_LOG, which sets the level until the client sends its settings
+        let filter = option("logLevel").and_then(|level| EnvFilter::try_new(level.as_str()?).ok());
+        if let (Some(filter), Some(handle)) = (filter, LOG_FILTER.get()) {
+            let _ = handle.reload(filter);
+        }
+    }
+}
+
+struct KLanguageServer {
+    client: Client,
+    // Shared with diagnostics tasks, which read loaded buffers after the debounce
+    documents: Arc<DashMap<Url, DocumentState>>,
+    open_documents: DashSet<Url>,
+    definitions: DashMap<Url, HashMap<String, Vec<Definition>>>,
+    config: RwLock<Config>,
+    client_capabilities: RwLock<ClientCapabilities>,
+    pending_diagnostics: DashMap<Url, AbortHandle>,
+    semantic_tokens_cache: DashMap<Url, (String, Vec<SemanticToken>)>,
+    next_result_id: AtomicU64,
+    loaded_files: DashMap<PathBuf, LoadedFile>,
+    running_script: Mutex<Option<AbortHandle>>,
+    workspace_folders: RwLock<Vec<PathBuf>>,
+}
+
+impl KLanguageServer {
+    fn new(client: Client) -> Self {
+        KLanguageServer {
+            client,
+            documents: Arc::new(DashMap::new()),
+            open_documents: DashSet::new(),
+            definitions: DashMap::new(),
+            config: RwLock::new(Config::default()),
+            client_capabilities: RwLock::new(ClientCapabilities::default()),
+            pending_diagnostics: DashMap::new(),
+            semantic_tokens_cache: DashMap::new(),
+            next_result_id: AtomicU64::new(0),
+            loaded_files: DashMap::new(),
+            running_script: Mutex::new(None),
+            workspace_folders: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn symbols_matching(&self, query: &str) -> Vec<SymbolInformation> {
+        let query = query.to_lowercase();
+        let mut matches = Vec::new();
+        let mut collect = |definitions: &HashMap<String, Vec<Definition>>| {
+            for (name, sites) in definitions {
+                let Some(definition) = sites.first() else {
+                    continue;
+                };
+                let lowercase = name.to_lowercase();
+                if fuzzy_match(&lowercase, &query) {
+                    matches.push((
+                        !lowercase.contains(&query),
+                        name.clone(),
+                        definition.clone(),
+                    ));
+                }
+            }
+        };
+
+        for entry in self.definitions.iter() {
+            collect(entry.value());
+        }
+        for entry in self.loaded_files.iter() {
+            let indexed = Url::from_file_path(entry.key())
+                .is_ok_and(|uri| self.definitions.contains_key(&uri));
+            if !indexed {
+                collect(&entry.value().definitions);
+            }
+        }
+
+        // Substring matches rank above scattered fuzzy matches
+        matches.sort_by(|a, b| (a.0, a.1.len(), &a.1).cmp(&(b.0, b.1.len(), &b.1)));
+        matches
+            .into_iter()
+            .take(MAX_WORKSPACE_SYMBOLS)
+            .map(|(_, name, definition)| {
+                #[allow(deprecated)]
+                SymbolInformation {
+                    name,
+                    kind: definition.kind.symbol_kind(),
+                    tags: None,
+                    deprecated: None,
+                    location: self.location_to_utf16(definition.location),
+                    container_name: None,
+                }
+            })
+            .collect()
+    }
+
+    fn cache_semantic_tokens(&self, uri: &Url, data: &[SemanticToken]) -> String {
+        let result_id = self.next_result_id.fetch_add(1, Ordering::Relaxed).to_string();
+        self.semantic_tokens_cache
+            .insert(uri.clone(), (result_id.clone(), data.to_vec()));
+        result_id
+    }
+
+    // Cloned so that no shard stays locked while a handler awaits or reads other documents
+    fn document_text(&self, uri: &Url) -> Option<String> {
+        self.documents
+            .get(uri)
+            .map(|document| document.text.clone())
+    }
+
+    // Definitions store byte columns; clients expect UTF-16 columns of the target document
+    fn location_to_utf16(&self, location: Location) -> Location {
+        match self.documents.get(&location.uri) {
+            Some(document) => Location {
+                range: LineIndex::new(&document.text).range_to_utf16(location.range),
+                uri: location.uri,
+            },
+            None => location,
+        }
+    }
+
+    // Definitions of a loaded file and its own \l targets; tracked documents use their buffer
+    async fn loaded_definitions(
+        &self,
+        path: &Path,
+    ) -> Option<(HashMap<String, Vec<Definition>>, Vec<PathBuf>)> {
+        let uri = Url::from_file_path(path).ok()?;
+        if let Some(text) = self.document_text(&uri) {
+            let definitions = match self.definitions.get(&uri) {
+                Some(definitions) => definitions.clone(),
+                None => parse(&text, &uri),
+            };
+            return Some((definitions, loaded_paths(&uri, &text)));
+        }
+
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        if let Some(cached) = self.loaded_files.get(path) {
+            if cached.modified == modified {
+                return Some((cached.definitions.clone(), cached.loads.clone()));
+            }
+        }
+        let text = tokio::fs::read_to_string(path).await.ok()?;
+        let loaded = LoadedFile {
+            modified,
+            definitions: parse(&text, &uri),
+            loads: loaded_paths(&uri, &text),
+        };
+        self.loaded_files.insert(path.to_path_buf(), loaded.clone());
+        Some((loaded.definitions, loaded.loads))
+    }
+
+    // Parameters of a user-defined function whose call brackets contain the position
+    fn function_signature_help(
+        &self,
+        document_uri: &Url,
+        text: &str,
+        position: Position,
+    ) -> Option<SignatureHelp> {
+        let (bracket, open) = open_bracket_at(text, position)?;
+        if bracket != "[" {
+            return None;
+        }
+        let tokens = lexer::tokenize(text);
+        let callee = tokens
+            .iter()
+            .take_while(|token| Position::new(token.line, token.start as u32) < open)
+            .last()
+            .filter(|token| {
+                token.kind == lexer::TokenKind::Identifier
+                    && token.line == open.line
+                    && (token.start + token.text.len()) as u32 == open.character
+            })?;
+
+        let function = |definitions: &HashMap<String, Vec<Definition>>| {
+            definitions
+                .get(callee.text)?
+                .iter()
+                .find(|site| site.kind == DefKind::Function)
+                .cloned()
+        };
+        let definition = self
+            .definitions
+            .get(document_uri)
+            .and_then(|definitions| function(&definitions))
+            .or_else(|| {
+                self.definitions
+                    .iter()
+                    .find_map(|definitions| function(&definitions))
+            })?;
+        let definition_text = if &definition.location.uri == document_uri {
+            text.to_string()
+        } else {
+            self.documents.get(&definition.location.uri)?.text.clone()
+        };
+        let parameters = lambda_parameters(&definition_text, definition.location.range.start);
+
+        // Arguments are separated by semicolons that are not nested in another bracket
+        let mut depth = 0;
+        let mut active_parameter = 0;
+        for token in tokens.iter().filter(|token| {
+            let start = Position::new(token.line, token.start as u32);
+            open < start && start < position
+        }) {
+            match token.text {
+                "{" | "[" | "(" => depth += 1,
+                "}" | "]" | ")" => depth -= 1,
+                ";" if depth == 0 => active_parameter += 1,
+                _ => {}
+            }
+        }
+
+        Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: format!("{}[{}]", callee.text, parameters.join(";")),
+                documentation: None,
+                parameters: Some(
+                    parameters
+                        .iter()
+                        .map(|parameter| ParameterInformation {
+                            label: ParameterLabel::Simple(parameter.clone()),
+                            documentation: None,
+                        })
+                        .collect(),
+                ),
+                active_parameter: None,
+            }],
+            active_signature: Some(0),
+            active_parameter: Some(active_parameter),
+        })
+    }
+
+    async fn run_line(&self, arguments: &[LSPAny]) -> Result<Option<LSPAny>> {
+        let (Some(document_uri), Some(line)) = (
+            command_uri(arguments),
+            arguments.get(1).and_then(|line| line.as_u64()),
+        ) else {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "Expected a document URI and a line number",
+            ));
+        };
+
+        let Some(doc_text) = self.document_text(&document_uri) else {
+            return Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError));
+        };
+        let end = expression_end(&doc_text, line as u32).line;
+        let source: Vec<&str> = doc_text
+            .lines()
+            .skip(line as usize)
+            .take((end as usize + 1).saturating_sub(line as usize))
+            .collect();
+
+        let config = self.config.read().unwrap().clone();
+        let Some(k_binary_path) = &config.k_binary_path else {
+            self.client
+                .show_message(MessageType::ERROR, "Could not find the K interpreter to run the line")
+                .await;
+            return Ok(None);
+        };
+        let working_dir = document_uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| path.parent().map(PathBuf::from));
+
+        let source = source.join("\n");
+        let timeout = config.diagnostics_timeout;
+        match evaluate(k_binary_path, working_dir.as_deref(), &source, timeout).await {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+                let stderr = String::from_utf8_lossy(&output.stderr).trim_end().to_string();
+                if output.status.success() && stderr.is_empty() {
+                    self.client.log_message(MessageType::INFO, &stdout).await;
+                    self.client.show_message(MessageType::INFO, stdout).await;
+                } else {
+                    let message = if stderr.is_empty() { stdout } else { stderr };
+                    self.client.log_message(MessageType::ERROR, &message).await;
+                    self.client.show_message(MessageType::ERROR, message).await;
+                }
+            }
+            Err(err) => {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!(
+                            "Failed to run the K interpreter at {}: {err}",
+                            k_binary_path.display()
+                        ),
+                    )
+                    .await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Evaluates the buffer as it is in the editor, including unsaved changes
+    async fn eval_file(&self, arguments: &[LSPAny]) -> Result<Option<LSPAny>> {
+        let Some(document_uri) = command_uri(arguments) else {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "Expected a document URI",
+            ));
+        };
+        let Some(doc_text) = self.document_text(&document_uri) else {
+            return Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError));
+        };
+
+        let config = self.config.read().unwrap().clone();
+        let Some(k_binary_path) = &config.k_binary_path else {
+            self.client
+                .show_message(
+                    MessageType::ERROR,
+                    "Could not find the K interpreter to evaluate the file",
+                )
+                .await;
+            return Ok(None);
+        };
+        let working_dir = document_uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| path.parent().map(PathBuf::from));
+
+        let timeout = config.diagnostics_timeout;
+        match evaluate(k_binary_path, working_dir.as_deref(), &doc_text, timeout).await {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout).trim_end().to_string();
+                let stderr = String::from_utf8_lossy(&output.stderr).trim_end().to_string();
+                if !stdout.is_empty() {
+                    self.client.log_message(MessageType::INFO, &stdout).await;
+                }
+                if !stderr.is_empty() {
+                    self.client.log_message(MessageType::ERROR, &stderr).await;
+                }
+                if output.status.success() && stderr.is_empty() {
+                    self.client.show_message(MessageType::INFO, stdout).await;
+                } else {
+                    let message = if stderr.is_empty() {
+                        format!("The K interpreter exited with {}", output.status)
+                    } else {
+                        stderr
+                    };
+                    self.client.show_message(MessageType::ERROR, message).await;
+                }
+            }
+            Err(err) => {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!(
+                            "Failed to run the K interpreter at {}: {err}",
+                            k_binary_path.display()
+                        ),
+                    )
+                    .await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn workspace_edit(&self, mut changes: Vec<(Url, Option<i32>, Vec<TextEdit>)>) -> WorkspaceEdit {
+        let document_changes_support = self
+            .client_capabilities
+            .read()
+            .unwrap()
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.workspace_edit.as_ref())
+            .and_then(|workspace_edit| workspace_edit.document_changes)
+            .unwrap_or(false);
+        // Versioned edits let the client reject the rename if a buffer changed meanwhile
+        if document_changes_support {
+            changes.sort_by(|a, b| a.0.cmp(&b.0));
+            let edits = changes
+                .into_iter()
+                .map(|(uri, version, edits)| TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+                    edits: edits.into_iter().map(OneOf::Left).collect(),
+                })
+                .collect();
+            return WorkspaceEdit {
+                changes: None,
+                document_changes: Some(DocumentChanges::Edits(edits)),
+                change_annotations: None,
+            };
+        }
+
+        WorkspaceEdit {
+            changes: Some(
+                changes
+                    .into_iter()
+                    .map(|(uri, _, edits)| (uri, edits))
+                    .collect(),
+            ),
+            document_changes: None,
+            change_annotations: None,
+        }
+    }
+
+    // Runs a trivial expression to confirm the configured interpreter works
+    async fn check_interpreter(&self) -> Result<Option<LSPAny>> {
+        let config = self.config.read().unwrap().clone();
+        let Some(k_binary_path) = &config.k_binary_path else {
+            self.client
+                .show_message(
+                    MessageType::ERROR,
+                    "Could not find the K interpreter; set kBinaryPath in initializationOptions \
+                     or KLSP_K_PATH",
+                )
+                .await;
+            return Ok(None);
+        };
+
+        match evaluate(k_binary_path, None, "1+1", config.diagnostics_timeout).await {
+            Ok(output) if output.status.success() => {
+                // The banner, which carries the version, is printed ahead of the result
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let version = stdout
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty() && *line != "2")
+                    .unwrap_or("unknown version")
+                    .to_string();
+                self.client
+                    .show_message(
+                        MessageType::INFO,
+                        format!("K interpreter at {}: {version}", k_binary_path.display()),
+                    )
+                    .await;
+                Ok(Some(LSPAny::String(version)))
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr)
+                    .trim_end()
+                    .to_string();
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!(
+                            "The K interpreter at {} exited with {}: {stderr}",
+                            k_binary_path.display(),
+                            output.status
+                        ),
+                    )
+                    .await;
+                Ok(None)
+            }
+            Err(err) => {
+                self.client
+                    .show_message(
+                        MessageType::ERROR,
+                        format!(
+                            "Failed to run the K interpreter at {}: {err}",
+                            k_binary_path.display()
+                        ),
+                    )
+                    .await;
+                Ok(None)
+            }
+        }
+    }
+
+    // Only one script runs at a time: starting a new run kills the previous one
+    async fn run_file(&self, arguments: &[LSPAny]) -> Result<Option<LSPAny>> {
+        let Some(path) = command_uri(arguments).and_then(|uri| uri.to_file_path().ok()) else {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "Expected the URI of a file on disk",
+            ));
+        };
+        let Some(k_binary_path) = self.config.read().unwrap().k_binary_path.clone() else {
+            self.client
+                .show_message(
+                    MessageType::ERROR,
+                    "Could not find the K interpreter to run the file",
+                )
+                .await;
+            return Ok(None);
+        };
+
+        let mut command = tokio::process::Command::new(&k_binary_path);
+        command
+            .arg(&path)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(working_dir) = path.parent() {
+            command.current_dir(working_dir);
+        }
+
+        let client = self.client.clone();
+        let task = tokio::spawn(async move {
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!(
+                                "Failed to run the K interpreter at {}: {err}",
+                                k_binary_path.display()
+                            ),
+                        )
+                        .await;
+                    return;
+                }
+            };
+            tokio::join!(
+                forward_lines(&client, child.stdout.take(), MessageType::INFO),
+                forward_lines(&client, child.stderr.take(), MessageType::ERROR),
+            );
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    client
+                        .log_message(
+                            MessageType::ERROR,
+                            format!("{} exited with {status}", path.display()),
+                        )
+                        .await;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    client
+                        .log_message(MessageType::ERROR, err.to_string())
+                        .await
+                }
+            }
+        });
+
+        if let Some(previous) = self
+            .running_script
+            .lock()
+            .unwrap()
+            .replace(task.abort_handle())
+        {
+            previous.abort();
+        }
+
+        Ok(None)
+    }
+
+    async fn index_workspace(&self, folders: Vec<PathBuf>) {
+        let mut pending = folders;
+        let mut indexed = 0;
+        while let Some(dir) = pending.pop() {
+            let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let Ok(metadata) = entry.metadata().await else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    pending.push(path);
+                } else if path.extension().is_some_and(|extension| extension == "k")
+                    && metadata.len() <= MAX_INDEXED_FILE_SIZE
+                {
+                    if indexed == MAX_INDEXED_FILES {
+                        tracing::warn!("stopped indexing the workspace after {indexed} files");
+                        return;
+                    }
+                    indexed += 1;
+                    let (Ok(uri), Ok(text)) = (
+                        Url::from_file_path(&path),
+                        tokio::fs::read_to_string(&path).await,
+                    ) else {
+                        continue;
+                    };
+                    if !self.documents.contains_key(&uri) {
+                        self.definitions.insert(uri.clone(), parse(&text, &uri));
+                        let document = DocumentState::new(&uri, text, None);
+                        self.documents.insert(uri, document);
+                    }
+                }
+            }
+        }
+    }
+
+    // Whether index_workspace would pick up the file, so its on-disk version stays indexed
+    fn is_indexed(&self, path: &Path) -> bool {
+        let hidden = |relative: &Path| {
+            relative
+                .components()
+                .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+        };
+        path.extension().is_some_and(|extension| extension == "k")
+            && self.workspace_folders.read().unwrap().iter().any(|folder| {
+                path.strip_prefix(folder)
+                    .is_ok_and(|relative| !hidden(relative))
+            })
+            && path
+                .metadata()
+                .is_ok_and(|metadata| metadata.len() <= MAX_INDEXED_FILE_SIZE)
+    }
+
+    // Files the document loads, following \l transitively. Tracked buffers win over disk,
+    // so unsaved edits are seen as typed.
+    async fn loaded_documents(&self, uri: &Url, text: &str) -> Vec<(Url, DocumentState)> {
+        let mut visited: HashSet<PathBuf> = uri.to_file_path().into_iter().collect();
+        let mut pending: VecDeque<PathBuf> = loaded_paths(uri, text).into();
+        let mut documents = Vec::new();
+        while let Some(path) = pending.pop_front() {
+            if !visited.insert(path.clone()) {
+                continue;
+            }
+            let Ok(uri) = Url::from_file_path(&path) else {
+                continue;
+            };
+            let tracked = self
+                .documents
+                .get(&uri)
+                .map(|document| document.value().clone());
+            let document = match tracked {
+                Some(document) => document,
+                None => match tokio::fs::read_to_string(&path).await {
+                    Ok(text) => DocumentState::new(&uri, text, None),
+                    Err(_) => continue,
+                },
+            };
+            pending.extend(document.loads.iter().cloned());
+            documents.push((uri, document));
+        }
+        documents
+    }
+
+    async fn warn_about_interpreter(&self) {
+        let k_binary_path = self.config.read().unwrap().k_binary_path.clone();
+        if let Some(message) = interpreter_problem(k_binary_path.as_deref()) {
+            self.client
+                .show_message(MessageType::WARNING, message)
+                .await;
+        }
+    }
+
+    // Tracked documents that load this one, directly or through other loads
+    fn dependents(&self, uri: &Url) -> Vec<Url> {
+        let Ok(path) = uri.to_file_path() else {
+            return vec![];
+        };
+        let mut targets = HashSet::from([path]);
+        let mut dependents: Vec<Url> = Vec::new();
+        loop {
+            let found: Vec<Url> = self
+                .documents
+                .iter()
+                .filter(|document| {
+                    document.key() != uri
+                        && !dependents.contains(document.key())
+                        && document.loads.iter().any(|path| targets.contains(path))
+                })
+                .map(|document| document.key().clone())
+                .collect();
+            if found.is_empty() {
+                break;
+            }
+            for dependent in found {
+                targets.extend(dependent.to_file_path());
+                dependents.push(dependent);
+            }
+        }
+        dependents
+    }
+
+    // Newer runs for the same document abort older ones, so stale results are never published
+    fn schedule_diagnostics(&self, uri: Url, debounce: bool) {
+        let config = self.config.read().unwrap().clone();
+        let Some(text) = self.document_text(&uri) else {
+            return;
+        };
+        let dependents: Vec<String> = self
+            .dependents(&uri)
+            .iter()
+            .filter_map(|dependent| self.document_text(dependent))
+            .collect();
+        let client = self.client.clone();
+        let documents = Arc::clone(&self.documents);
+        let task_uri = uri.clone();
+        let task = tokio::spawn(async move {
+            if debounce {
+                tokio::time::sleep(config.diagnostics_debounce).await;
+            }
+            let loaded = loaded_names(&task_uri, &text, &documents).await;
+            let used_elsewhere = identifier_names(&dependents);
+            publish_diagnostics(&client, &config, task_uri, &text, &loaded, &used_elsewhere).await;
+        });
+
+        if let Some(previous) = self.pending_diagnostics.insert(uri, task.abort_handle()) {
+            previous.abort();
+        }
+    }
+}
+
+fn identifier_names(texts: &[String]) -> HashSet<String> {
+    texts
+        .iter()
+        .flat_map(|text| lexer::tokenize(text))
+        .filter(|token| token.kind == lexer::TokenKind::Identifier)
+        .map(|token| token.text.to_string())
+        .collect()
+}
+
+fn command_uri(arguments: &[LSPAny]) -> Option<Url> {
+    arguments
+        .first()
+        .and_then(|uri| uri.as_str())
+        .and_then(|uri| Url::parse(uri).ok())
+}
+
+async fn forward_lines<R: tokio::io::AsyncRead + Unpin>(
+    client: &Client,
+    stream: Option<R>,
+    message_type: MessageType,
+) {
+    let Some(stream) = stream else {
+        return;
+    };
+    let mut lines = tokio::io::AsyncBufReadExt::lines(tokio::io::BufReader::new(stream));
+    while let Ok(Some(line)) = lines.next_line().await {
+        client.log_message(message_type, line).await;
+    }
+}
+
+// Definitions of each file the document loads, following \l transitively, nearest first.
+// The visited set stops \l cycles.
+async fn transitive_loads<F, Fut>(
+    uri: &Url,
+    text: &str,
+    mut load: F,
+) -> Vec<HashMap<String, Vec<Definition>>>
+where
+    F: FnMut(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = Option<(HashMap<String, Vec<Definition>>, Vec<PathBuf>)>>,
+{
+    let mut visited: HashSet<PathBuf> = uri.to_file_path().into_iter().collect();
+    let mut pending: VecDeque<PathBuf> = loaded_paths(uri, text).into();
+    let mut loaded = Vec::new();
+    while let Some(path) = pending.pop_front() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let Some((definitions, loads)) = load(path).await else {
+            continue;
+        };
+        loaded.push(definitions);
+        pending.extend(loads);
+    }
+    loaded
+}
+
+// Tracked buffers win over disk, so names just added to an open file count before it is saved
+async fn loaded_names(
+    uri: &Url,
+    text: &str,
+    documents: &DashMap<Url, DocumentState>,
+) -> HashSet<String> {
+    let loaded = transitive_loads(uri, text, |path| async move {
+        let uri = Url::from_file_path(&path).ok()?;
+        let tracked = documents.get(&uri).map(|document| document.value().clone());
+        let document = match tracked {
+            Some(document) => document,
+            None => DocumentState::new(&uri, tokio::fs::read_to_string(&path).await.ok()?, None),
+        };
+        Some((parse(&document.text, &uri), document.loads))
+    })
+    .await;
+    loaded.into_iter().flat_map(HashMap::into_keys).collect()
+}
+
+async fn publish_diagnostics(
+    client: &Client,
+    config: &Config,
+    uri: Url,
+    text: &str,
+    loaded_names: &HashSet<String>,
+    used_elsewhere: &HashSet<String>,
+) {
+    let mut diagnostics = undefined_name_diagnostics(text, loaded_names);
+    diagnostics.extend(unused_definition_diagnostics(text, used_elsewhere));
+    match uri.to_file_path() {
+        Ok(path) => {
+            let working_dir = path.parent().map(PathBuf::from);
+            diagnostics.extend(get_diagnostics(config, &uri, working_dir.as_deref(), text).await);
+        }
+        Err(_) => {
+            client
+                .log_message(
+                    MessageType::LOG,
+                    format!("Skipping interpreter diagnostics for {uri}: not a file on disk"),
+                )
+                .await;
+        }
+    }
+    let index = LineIndex::new(text);
+    for diagnostic in &mut diagnostics {
+        diagnostic.range = index.range_to_utf16(diagnostic.range);
+        for related in diagnostic.related_information.iter_mut().flatten() {
+            related.location.range = index.range_to_utf16(related.location.range);
+        }
+    }
+    client.publish_diagnostics(uri, diagnostics, None).await;
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for KLanguageServer {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        self.config
+            .write()
+            .unwrap()
+            .apply_settings(params.initialization_options.as_ref());
+        *self.client_capabilities.write().unwrap() = params.capabilities;
+
+        #[allow(deprecated)]
+        let folders: Vec<PathBuf> = match (params.workspace_folders, params.root_uri) {
+            (Some(folders), _) => folders
+                .into_iter()
+                .filter_map(|folder| folder.uri.to_file_path().ok())
+                .collect(),
+            (None, Some(root)) => root.to_file_path().into_iter().collect(),
+            (None, None) => vec![],
+        };
+        *self.workspace_folders.write().unwrap() = folders;
+
+        Ok(InitializeResult {
+            server_info: Some(ServerInfo {
+                name: "K Language Server".to_string(),
+                version: None,
+            }),
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true),
+                        })),
+                        ..TextDocumentSyncOptions::default()
+                    },
+                )),
+                definition_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                })),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(
+                        ('a'..='z')
+                            .chain('A'..='Z')
+                            .map(|c| c.to_string())
+                            .collect(),
+                    ),
+                    ..CompletionOptions::default()
+                }),
+                references_provider: Some(OneOf::Left(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                document_highlight_provider: Some(OneOf::Left(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                document_range_formatting_provider: Some(OneOf::Left(true)),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: ";".to_string(),
+                    more_trigger_character: Some(vec!["\n".to_string()]),
+                }),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                        ..CodeActionOptions::default()
+                    },
+                )),
+                code_lens_provider: Some(CodeLensOptions {
+                    resolve_provider: Some(false),
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        RUN_LINE_COMMAND.to_string(),
+                        RUN_FILE_COMMAND.to_string(),
+                        EVAL_FILE_COMMAND.to_string(),
+                        CHECK_INTERPRETER_COMMAND.to_string(),
+                    ],
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                signature_help_provider: Some(SignatureHelpOptions {
+                    trigger_characters: Some(
+                        builtins::verbs()
+                            .map(|builtin| builtin.name.to_string())
+                            .chain(["[".to_string(), ";".to_string()])
+                            .collect(),
+                    ),
+                    ..SignatureHelpOptions::default()
+                }),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                        legend: SemanticTokensLegend {
+                            token_types: SEMANTIC_TOKEN_TYPES.to_vec(),
+                            token_modifiers: vec![],
+                        },
+                        range: Some(true),
+                        full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                        ..SemanticTokensOptions::default()
+                    }),
+                ),
+                ..ServerCapabilities::default()
+            },
+        })
+    }
+
+    async fn initialized(&self, _: InitializedParams) {
+        let registration = Registration {
+            id: "klsp-watch-k-files".to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![FileSystemWatcher {
+                    glob_pattern: GlobPattern::String("**/*.k".to_string()),
+                    kind: None,
+                }],
+            })
+            .ok(),
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            tracing::warn!(%err, "could not register a file watcher");
+        }
+        self.warn_about_interpreter().await;
+        // Indexed after the handshake so a large workspace doesn't hold up initialize
+        let folders = self.workspace_folders.read().unwrap().clone();
+        self.index_workspace(folders).await;
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let position = index.to_bytes(params.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let (start, end) = variable_range_at_position(line_text, position.character);
+            let variable_name = &line_text[start..end];
+            // Strings, comments and numbers lex as something other than an identifier
+            let is_identifier = lexer::tokenize(&doc_text).iter().any(|token| {
+                token.line == position.line
+                    && token.start == start
+                    && token.kind == lexer::TokenKind::Identifier
+            });
+            let is_local = innermost_scope(&lambda_scopes(&doc_text), position)
+                .is_some_and(|scope| scope.declares(variable_name));
+            let is_definition = is_identifier
+                && builtins::lookup(variable_name).is_none()
+                && (is_local
+                    || self
+                        .definitions
+                        .iter()
+                        .any(|definitions| definitions.contains_key(variable_name))
+                    || loaded_names(&document_uri, &doc_text, &self.documents)
+                        .await
+                        .contains(variable_name));
+
+            if !is_definition {
+                return Ok(None);
+            }
+            Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+                range: index.range_to_utf16(Range {
+                    start: Position::new(position.line, start as u32),
+                    end: Position::new(position.line, end as u32),
+                }),
+                placeholder: variable_name.to_string(),
+            }))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(uri = %params.text_document_position.text_document.uri)
+    )]
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let document_uri = params.text_document_position.text_document.uri;
+        let new_name = params.new_name;
+
+        if !IDENTIFIER_RE.is_match(&new_name) {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "'{new_name}' is not a valid K identifier: expected a letter followed by letters, \
+                 digits or underscores, optionally namespaced like .ns.name"
+            )));
+        }
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let position =
+                LineIndex::new(&doc_text).to_bytes(params.text_document_position.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let variable_name = extract_variable_at_position(line_text, position.character);
+
+            // Parameters and locals are renamed only where the same lambda binds them
+            if local_declarations(&doc_text, variable_name, position).is_some() {
+                let index = LineIndex::new(&doc_text);
+                let edits = binding_occurrences(&doc_text, variable_name, position)
+                    .into_iter()
+                    .map(|range| TextEdit {
+                        range: index.range_to_utf16(range),
+                        new_text: new_name.clone(),
+                    })
+                    .collect();
+                let version = self
+                    .documents
+                    .get(&document_uri)
+                    .and_then(|document| document.version);
+                let changes = vec![(document_uri, version, edits)];
+                return Ok(Some(self.workspace_edit(changes)));
+            }
+
+            // Only files that share definitions through \l: the document, what it loads and
+            // what loads it. Unrelated workspace files may define the same name for themselves.
+            let version = self
+                .documents
+                .get(&document_uri)
+                .and_then(|document| document.version);
+            let mut documents = vec![(
+                document_uri.clone(),
+                DocumentState::new(&document_uri, doc_text.clone(), version),
+            )];
+            documents.extend(self.loaded_documents(&document_uri, &doc_text).await);
+            for dependent in self.dependents(&document_uri) {
+                if documents.iter().any(|(uri, _)| *uri == dependent) {
+                    continue;
+                }
+                if let Some(document) = self.documents.get(&dependent) {
+                    let document = document.value().clone();
+                    documents.push((dependent, document));
+                }
+            }
+
+            let mut changes = Vec::new();
+
+            let defined = documents.iter().any(|(_, document)| {
+                definition_sites(&document.text)
+                    .into_iter()
+                    .any(|(name, ..)| name == variable_name)
+            });
+            if defined {
+                let rename_in_comments = self.config.read().unwrap().rename_in_comments;
+                for (uri, document) in documents {
+                    let text = &document.text;
+                    let index = LineIndex::new(text);
+                    let occurrences = if rename_in_comments {
+                        find_occurrences_outside(text, variable_name, &lexer::string_spans(text))
+                    } else {
+                        find_occurrences(text, variable_name)
+                    };
+                    let scopes = lambda_scopes(text);
+                    let edits: Vec<TextEdit> = occurrences
+                        .into_iter()
+                        .filter(|range| {
+                            !innermost_scope(&scopes, range.start)
+                                .is_some_and(|scope| scope.declares(variable_name))
+                        })
+                        .map(|range| TextEdit {
+                            range: index.range_to_utf16(range),
+                            new_text: new_name.clone(),
+                        })
+                        .collect();
+                    if !edits.is_empty() {
+                        changes.push((uri, document.version, edits));
+                    }
+                }
+            }
+
+            Ok(Some(self.workspace_edit(changes)))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let document_uri = params.text_document_position.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let position = index.to_bytes(params.text_document_position.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let variable_name = extract_variable_at_position(line_text, position.character);
+            let declarations: Vec<Range> = local_declarations(&doc_text, variable_name, position)
+                .or_else(|| {
+                    let definitions = self.definitions.get(&document_uri)?;
+                    let sites = definitions.get(variable_name)?;
+                    Some(sites.iter().map(|site| site.location.range).collect())
+                })
+                .unwrap_or_default();
+
+            let locations = binding_occurrences(&doc_text, variable_name, position)
+                .into_iter()
+                .filter(|range| params.context.include_declaration || !declarations.contains(range))
+                .map(|range| Location {
+                    uri: document_uri.clone(),
+                    range: index.range_to_utf16(range),
+                })
+                .collect();
+
+            Ok(Some(locations))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        let document_uri = params.text_document_position_params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let position = index.to_bytes(params.text_document_position_params.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let variable_name = extract_variable_at_position(line_text, position.character);
+            let definition_starts: Vec<Position> =
+                match local_declarations(&doc_text, variable_name, position) {
+                    Some(declarations) => declarations.iter().map(|range| range.start).collect(),
+                    None => definition_sites(&doc_text)
+                        .into_iter()
+                        .filter(|&(var_name, _, _, _)| var_name == variable_name)
+                        .map(|(_, line_number, column, _)| Position::new(line_number, column))
+                        .collect(),
+                };
+
+            let highlights = binding_occurrences(&doc_text, variable_name, position)
+                .into_iter()
+                .map(|range| DocumentHighlight {
+                    range: index.range_to_utf16(range),
+                    kind: Some(
+                        if definition_starts.contains(&range.start) {
+                            DocumentHighlightKind::WRITE
+                        } else {
+                            DocumentHighlightKind::READ
+                        },
+                    ),
+                })
+                .collect();
+
+            Ok(Some(highlights))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let document_uri = params.text_document_position_params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let position = index.to_bytes(params.text_document_position_params.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let (start, token) = extract_token_at_position(line_text, position.character);
+
+            let value = if let Some(builtin) = builtins::lookup(token) {
+                builtin.markdown()
+            } else if let Some(line_number) = local_definition(&doc_text, token, position)
+                .map(|range| range.start.line)
+                .or_else(|| {
+                    let definitions = self.definitions.get(&document_uri)?;
+                    nearest_definition(definitions.get(token)?, position.line)
+                        .map(|definition| definition.location.range.start.line)
+                })
+            {
+                let definition_line = doc_text.lines().nth(line_number as usize).unwrap_or("");
+                format!("```k\n{definition_line}\n```\n\nDefined on line {}", line_number + 1)
+            } else {
+                return Ok(None);
+            };
+
+            Ok(Some(Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value,
+                }),
+                range: Some(index.range_to_utf16(Range {
+                    start: Position::new(position.line, start as u32),
+                    end: Position::new(position.line, (start + token.len()) as u32),
+                })),
+            }))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let document_uri = params.text_document_position.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            if let Some(definitions) = self.definitions.get(&document_uri) {
+                let index = LineIndex::new(&doc_text);
+                let position = index.to_bytes(params.text_document_position.position);
+                let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+                let (start, _) = variable_range_at_position(line_text, position.character);
+                let prefix = line_text
+                    .get(start..position.character as usize)
+                    .unwrap_or("");
+                let range = index.range_to_utf16(Range {
+                    start: Position::new(position.line, start as u32),
+                    end: position,
+                });
+
+                let mut items: Vec<CompletionItem> = definitions
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(prefix))
+                    .filter_map(|(name, sites)| Some((name, sites.first()?)))
+                    .map(|(name, definition)| CompletionItem {
+                        label: name.clone(),
+                        kind: Some(definition.kind.completion_kind()),
+                        detail: doc_text
+                            .lines()
+                            .nth(definition.location.range.start.line as usize)
+                            .map(str::to_owned),
+                        sort_text: Some(format!("0{name}")),
+                        text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                            range,
+                            new_text: name.clone(),
+                        })),
+                        ..CompletionItem::default()
+                    })
+                    .collect();
+                let builtins = builtins::BUILTINS.iter().filter(|builtin| {
+                    builtin.name.starts_with(prefix) && !definitions.contains_key(builtin.name)
+                });
+                items.extend(builtins.map(|builtin| CompletionItem {
+                    label: builtin.name.to_string(),
+                    kind: Some(match builtin.kind {
+                        builtins::BuiltinKind::Function => CompletionItemKind::FUNCTION,
+                        _ => CompletionItemKind::KEYWORD,
+                    }),
+                    detail: Some(builtin.summary.to_string()),
+                    documentation: Some(Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value: builtin.markdown(),
+                    })),
+                    sort_text: Some(format!("1{}", builtin.name)),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: builtin.name.to_string(),
+                    })),
+                    ..CompletionItem::default()
+                }));
+
+                let snippet_support = self
+                    .client_capabilities
+                    .read()
+                    .unwrap()
+                    .text_document
+                    .as_ref()
+                    .and_then(|text_document| text_document.completion.as_ref())
+                    .and_then(|completion| completion.completion_item.as_ref())
+                    .and_then(|completion_item| completion_item.snippet_support)
+                    .unwrap_or(false);
+                let snippets = builtins::SNIPPETS
+                    .iter()
+                    .filter(|snippet| snippet.label.starts_with(prefix));
+                items.extend(snippets.map(|snippet| CompletionItem {
+                    label: snippet.label.to_string(),
+                    kind: Some(CompletionItemKind::SNIPPET),
+                    detail: Some(snippet.detail.to_string()),
+                    sort_text: Some(format!("2{}", snippet.label)),
+                    insert_text_format: Some(if snippet_support {
+                        InsertTextFormat::SNIPPET
+                    } else {
+                        InsertTextFormat::PLAIN_TEXT
+                    }),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: if snippet_support {
+                            snippet.body.to_string()
+                        } else {
+                            strip_snippet_placeholders(snippet.body)
+                        },
+                    })),
+                    ..CompletionItem::default()
+                }));
+
+                Ok(Some(CompletionResponse::Array(items)))
+            } else {
+                Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::InternalError))
+            }
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            if let Some(definitions) = self.definitions.get(&document_uri) {
+                let index = LineIndex::new(&doc_text);
+                let mut definitions: Vec<_> = definitions
+                    .iter()
+                    .flat_map(|(name, sites)| sites.iter().map(move |site| (name, site)))
+                    .collect();
+                definitions.sort_by_key(|(_, definition)| definition.location.range.start);
+
+                #[allow(deprecated)]
+                let symbols = definitions
+                    .into_iter()
+                    .map(|(name, definition)| {
+                        let location = &definition.location;
+                        DocumentSymbol {
+                            name: name.clone(),
+                            detail: None,
+                            kind: definition.kind.symbol_kind(),
+                            tags: None,
+                            deprecated: None,
+                            range: index.range_to_utf16(Range {
+                                start: location.range.start,
+                                end: expression_end(&doc_text, location.range.start.line),
+                            }),
+                            selection_range: index.range_to_utf16(location.range),
+                            children: None,
+                        }
+                    })
+                    .collect();
+
+                Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+            } else {
+                Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::InternalError))
+            }
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let data = self
+                .definitions
+                .get(&document_uri)
+                .map(|definitions| semantic_tokens(&doc_text, &definitions, None))
+                .unwrap_or_default();
+            let result_id = self.cache_semantic_tokens(&document_uri, &data);
+
+            Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+                result_id: Some(result_id),
+                data,
+            })))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let data = self
+                .definitions
+                .get(&document_uri)
+                .map(|definitions| semantic_tokens(&doc_text, &definitions, None))
+                .unwrap_or_default();
+            let previous = self
+                .semantic_tokens_cache
+                .get(&document_uri)
+                .filter(|cached| cached.0 == params.previous_result_id)
+                .map(|cached| semantic_tokens_edits(&cached.1, &data));
+            let result_id = self.cache_semantic_tokens(&document_uri, &data);
+
+            Ok(Some(match previous {
+                Some(edits) => SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                    result_id: Some(result_id),
+                    edits,
+                }),
+                None => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                    result_id: Some(result_id),
+                    data,
+                }),
+            }))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> Result<Option<SemanticTokensRangeResult>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let data = self
+                .definitions
+                .get(&document_uri)
+                .map(|definitions| semantic_tokens(&doc_text, &definitions, Some(params.range)))
+                .unwrap_or_default();
+
+            Ok(Some(SemanticTokensRangeResult::Tokens(SemanticTokens {
+                result_id: None,
+                data,
+            })))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let formatter_path = self.config.read().unwrap().formatter_path.clone();
+            let index = LineIndex::new(&doc_text);
+            let to_utf16 = |edits: Vec<TextEdit>| {
+                edits
+                    .into_iter()
+                    .map(|edit| TextEdit {
+                        range: index.range_to_utf16(edit.range),
+                        ..edit
+                    })
+                    .collect()
+            };
+            let Some(formatter_path) = formatter_path else {
+                return Ok(Some(to_utf16(line_edits(
+                    &doc_text,
+                    &format_text(&doc_text),
+                ))));
+            };
+            match run_formatter(&formatter_path, &doc_text).await {
+                Ok(formatted) => Ok(Some(to_utf16(line_edits(&doc_text, &formatted)))),
+                Err(err) => {
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Formatter {} failed: {err}", formatter_path.display()),
+                        )
+                        .await;
+                    Ok(None)
+                }
+            }
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let range = index.range_to_bytes(params.range);
+            let lines: Vec<&str> = doc_text.lines().collect();
+            // Only lines wholly inside the selection are touched
+            let first = range.start.line as usize + usize::from(range.start.character > 0);
+            let end = match lines.get(range.end.line as usize) {
+                Some(line) if (range.end.character as usize) < line.len() => {
+                    range.end.line as usize
+                }
+                Some(_) => range.end.line as usize + 1,
+                None => lines.len(),
+            };
+            if first >= end {
+                return Ok(Some(vec![]));
+            }
+
+            let formatter_path = self.config.read().unwrap().formatter_path.clone();
+            let Some(formatter_path) = formatter_path else {
+                let edits = format_lines(&doc_text, first..end)
+                    .into_iter()
+                    .zip(first..end)
+                    .filter(|(formatted, line)| formatted != lines[*line])
+                    .map(|(formatted, line)| TextEdit {
+                        range: index.range_to_utf16(Range::new(
+                            Position::new(line as u32, 0),
+                            Position::new(line as u32, lines[line].len() as u32),
+                        )),
+                        new_text: formatted,
+                    })
+                    .collect();
+                return Ok(Some(edits));
+            };
+            let selected = lines[first..end].join("\n");
+            match run_formatter(&formatter_path, &selected).await {
+                Ok(formatted) => Ok(Some(vec![TextEdit {
+                    range: index.range_to_utf16(Range::new(
+                        Position::new(first as u32, 0),
+                        Position::new(end as u32 - 1, lines[end - 1].len() as u32),
+                    )),
+                    new_text: formatted.trim_end_matches(['\r', '\n']).to_string(),
+                }])),
+                Err(err) => {
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("Formatter {} failed: {err}", formatter_path.display()),
+                        )
+                        .await;
+                    Ok(None)
+                }
+            }
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn on_type_formatting(
+        &self,
+        params: DocumentOnTypeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let document_uri = params.text_document_position.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let position = index.to_bytes(params.text_document_position.position);
+            let space_after_semicolon = match params.options.properties.get("spaceAfterSemicolon") {
+                Some(FormattingProperty::Bool(enabled)) => *enabled,
+                _ => self.config.read().unwrap().space_after_semicolon,
+            };
+            let edit = match params.ch.as_str() {
+                ";" if space_after_semicolon => semicolon_edit(&doc_text, position),
+                "\n" => newline_indent_edit(&doc_text, position, &params.options),
+                _ => None,
+            };
+            Ok(edit.map(|edit| {
+                vec![TextEdit {
+                    range: index.range_to_utf16(edit.range),
+                    ..edit
+                }]
+            }))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            Ok(Some(folding_ranges(&doc_text)))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            fn to_utf16(index: &LineIndex, selection: SelectionRange) -> SelectionRange {
+                SelectionRange {
+                    range: index.range_to_utf16(selection.range),
+                    parent: selection
+                        .parent
+                        .map(|parent| Box::new(to_utf16(index, *parent))),
+                }
+            }
+            Ok(Some(
+                params
+                    .positions
+                    .into_iter()
+                    .map(|position| selection_range_at(&doc_text, index.to_bytes(position)))
+                    .map(|selection| to_utf16(&index, selection))
+                    .collect(),
+            ))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn signature_help(&self, params: SignatureHelpParams) -> Result<Option<SignatureHelp>> {
+        let document_uri = params.text_document_position_params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let position =
+                LineIndex::new(&doc_text).to_bytes(params.text_document_position_params.position);
+            let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+            let tokens = lexer::tokenize(line_text);
+            let cursor = position.character as usize;
+            // A verb just before the cursor wins over the call it is an argument of
+            let verb = tokens
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, token)| token.start < cursor)
+                .take(1)
+                .find_map(|(index, token)| {
+                    builtins::verbs()
+                        .find(|builtin| builtin.name == token.text)
+                        .map(|builtin| (index, builtin))
+                });
+            let Some((verb_index, builtin)) = verb else {
+                return Ok(self.function_signature_help(&document_uri, &doc_text, position));
+            };
+
+            let valence = self
+                .definitions
+                .get(&document_uri)
+                .map_or(Valence::Ambiguous, |definitions| {
+                    verb_valence(&tokens, verb_index, &definitions)
+                });
+            let signatures = builtin_signatures(builtin, valence);
+            if signatures.is_empty() {
+                return Ok(self.function_signature_help(&document_uri, &doc_text, position));
+            }
+
+            Ok(Some(SignatureHelp {
+                signatures,
+                active_signature: Some(0),
+                active_parameter: None,
+            }))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let document_uri = params.text_document.uri;
+
+        if !self.config.read().unwrap().implicit_parameter_hints {
+            return Ok(None);
+        }
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let range = index.range_to_bytes(params.range);
+            Ok(Some(
+                implicit_parameter_hints(&doc_text)
+                    .into_iter()
+                    .filter(|hint| range.start <= hint.position && hint.position <= range.end)
+                    .map(|hint| InlayHint {
+                        position: index.to_utf16(hint.position),
+                        ..hint
+                    })
+                    .collect(),
+            ))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        let settings = params.settings.get("klsp").unwrap_or(&params.settings);
+        self.config.write().unwrap().apply_settings(Some(settings));
+        if settings.get("kBinaryPath").is_some() {
+            self.warn_about_interpreter().await;
+        }
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        Ok(Some(self.symbols_matching(&params.query)))
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let index = LineIndex::new(&doc_text);
+            let mut seen = HashSet::new();
+            let actions = params
+                .context
+                .diagnostics
+                .into_iter()
+                .filter(|diagnostic| {
+                    diagnostic.range.start <= params.range.end
+                        && params.range.start <= diagnostic.range.end
+                })
+                .filter_map(|diagnostic| {
+                    let name = diagnostic.data.as_ref()?.as_str()?.to_string();
+                    let (title, edit) = match &diagnostic.code {
+                        Some(NumberOrString::String(code)) if code == UNDEFINED_NAME_CODE => {
+                            if !seen.insert(name.clone()) {
+                                return None;
+                            }
+                            (
+                                format!("Define '{name}'"),
+                                define_stub_edit(&doc_text, &name)?,
+                            )
+                        }
+                        Some(NumberOrString::String(code)) if code == UNUSED_DEFINITION_CODE => (
+                            format!("Remove unused definition '{name}'"),
+                            remove_definition_edit(
+                                &doc_text,
+                                index.to_bytes(diagnostic.range.start),
+                            )?,
+                        ),
+                        _ => return None,
+                    };
+                    let edit = TextEdit {
+                        range: index.range_to_utf16(edit.range),
+                        ..edit
+                    };
+                    Some(CodeActionOrCommand::CodeAction(CodeAction {
+                        title,
+                        kind: Some(CodeActionKind::QUICKFIX),
+                        diagnostics: Some(vec![diagnostic]),
+                        edit: Some(WorkspaceEdit {
+                            changes: Some(HashMap::from([(document_uri.clone(), vec![edit])])),
+                            ..WorkspaceEdit::default()
+                        }),
+                        ..CodeAction::default()
+                    }))
+                })
+                .collect();
+            Ok(Some(actions))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        let document_uri = params.text_document.uri;
+
+        if let Some(doc_text) = self.document_text(&document_uri) {
+            let lenses = expression_lines(&doc_text)
+                .into_iter()
+                .map(|(line, _)| CodeLens {
+                    range: Range::new(Position::new(line, 0), Position::new(line, 0)),
+                    command: Some(Command {
+                        title: "▶ Run".to_string(),
+                        command: RUN_LINE_COMMAND.to_string(),
+                        arguments: Some(vec![
+                            LSPAny::from(document_uri.to_string()),
+                            LSPAny::from(line),
+                        ]),
+                    }),
+                    data: None,
+                })
+                .collect();
+            Ok(Some(lenses))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError))
+        }
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<LSPAny>> {
+        match params.command.as_str() {
+            RUN_LINE_COMMAND => self.run_line(&params.arguments).await,
+            RUN_FILE_COMMAND => self.run_file(&params.arguments).await,
+            EVAL_FILE_COMMAND => self.eval_file(&params.arguments).await,
+            CHECK_INTERPRETER_COMMAND => self.check_interpreter().await,
+            command => Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "Unknown command {command}"
+            ))),
+        }
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        // Dropping the aborted task kills the child process
+        if let Some(running) = self.running_script.lock().unwrap().take() {
+            running.abort();
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(uri = %params.text_document.uri))]
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let text = params.text_document.text;
+        self.documents.insert(
+            uri.clone(),
+            DocumentState::new(&uri, text.clone(), Some(params.text_document.version)),
+        );
+        self.open_documents.insert(uri.clone());
+        let definitions = parse(&text, &uri);
+        self.definitions.insert(uri.clone(), definitions);
+        self.schedule_diagnostics(uri, false);
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        let mut changed_paths = Vec::new();
+        for change in params.changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            self.loaded_files.remove(&path);
+            // Open documents follow the editor's buffer rather than the file on disk
+            if !self.open_documents.contains(&change.uri) {
+                let text = match change.typ {
+                    FileChangeType::DELETED => None,
+                    _ => tokio::fs::read_to_string(&path).await.ok(),
+                };
+                match text {
+                    Some(text) => {
+                        self.definitions
+                            .insert(change.uri.clone(), parse(&text, &change.uri));
+                        let document = DocumentState::new(&change.uri, text, None);
+                        self.documents.insert(change.uri, document);
+                    }
+                    None => {
+                        self.definitions.remove(&change.uri);
+                        self.documents.remove(&change.uri);
+                    }
+                }
+            }
+            changed_paths.push(path);
+        }
+
+        let dependents: Vec<Url> = self
+            .open_documents
+            .iter()
+            .filter(|uri| {
+                self.documents.get(uri.key()).is_some_and(|document| {
+                    document
+                        .loads
+                        .iter()
+                        .any(|path| changed_paths.contains(path))
+                })
+            })
+            .map(|uri| uri.key().clone())
+            .collect();
+        for uri in dependents {
+            self.schedule_diagnostics(uri, true);
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(uri = %params.text_document.uri))]
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri;
+        if params.content_changes.is_empty() {
+            tracing::warn!(%uri, "ignoring a change notification without content changes");
+            return;
+        }
+        let mut text = self
+            .documents
+            .get(&uri)
+            .map(|document| document.text.clone())
+            .unwrap_or_default();
+        for change in params.content_changes {
+            match change.range {
+                Some(range) => {
+                    let start = offset_at(&text, range.start);
+                    let end = offset_at(&text, range.end).max(start);
+                    text.replace_range(start..end, &change.text);
+                }
+                None => text = change.text,
+            }
+        }
+        self.documents.insert(
+            uri.clone(),
+            DocumentState::new(&uri, text.clone(), Some(params.text_document.version)),
+        );
+        let definitions = parse(&text, &uri);
+        self.definitions.insert(uri.clone(), definitions);
+
+        self.schedule_diagnostics(uri, true);
+    }
+
+    // The file on disk now matches the buffer, so files loading it see the same definitions
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri;
+        if let Ok(path) = uri.to_file_path() {
+            self.loaded_files.remove(&path);
+        }
+        if let Some(text) = params.text {
+            let version = self
+                .documents
+                .get(&uri)
+                .and_then(|document| document.version);
+            self.definitions.insert(uri.clone(), parse(&text, &uri));
+            self.documents
+                .insert(uri.clone(), DocumentState::new(&uri, text, version));
+        }
+        self.schedule_diagnostics(uri, false);
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.open_documents.remove(&uri);
+        self.semantic_tokens_cache.remove(&uri);
+        // Workspace files go back to their saved contents instead of leaving the index
+        let saved = match uri.to_file_path() {
+            Ok(path) if self.is_indexed(&path) => tokio::fs::read_to_string(&path).await.ok(),
+            _ => None,
+        };
+        match saved {
+            Some(text) => {
+                self.definitions.insert(uri.clone(), parse(&text, &uri));
+                self.documents
+                    .insert(uri.clone(), DocumentState::new(&uri, text, None));
+            }
+            None => {
+                self.documents.remove(&uri);
+                self.definitions.remove(&uri);
+            }
+        }
+        if let Some((_, pending)) = self.pending_diagnostics.remove(&uri) {
+            pending.abort();
+        }
+        // Clear the problems panel, since the diagnostics would no longer be kept up to date
+        self.client.publish_diagnostics(uri, Vec::new(), None).await;
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(uri = %params.text_document_position_params.text_document.uri)
+    )]
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let document_uri = params.text_document_position_params.text_document.uri;
+
+        let Some(doc_text) = self.document_text(&document_uri) else {
+            return Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::ParseError));
+        };
+        let index = LineIndex::new(&doc_text);
+        let position = index.to_bytes(params.text_document_position_params.position);
+        let line_text = doc_text.lines().nth(position.line as usize).unwrap_or("");
+        let variable_name = extract_variable_at_position(line_text, position.character);
+
+        let range = if let Some(definitions) = self.definitions.get(&document_uri) {
+            local_definition(&doc_text, variable_name, position).or_else(|| {
+                nearest_definition(definitions.get(variable_name)?, position.line)
+                    .map(|definition| definition.location.range)
+            })
+        } else {
+            return Err(tower_lsp::jsonrpc::Error::new(tower_lsp::jsonrpc::ErrorCode::InternalError));
+        };
+        if let Some(range) = range {
+            return Ok(Some(GotoDefinitionResponse::Scalar(Location {
+                uri: document_uri,
+                range: index.range_to_utf16(range),
+            })));
+        }
+
+        let loaded = transitive_loads(&document_uri, &doc_text, |path| async move {
+            self.loaded_definitions(&path).await
+        })
+        .await;
+        let mut locations = Vec::new();
+        for definitions in loaded {
+            if let Some(sites) = definitions.get(variable_name) {
+                locations.extend(
+                    sites
+                        .iter()
+                        .map(|site| self.location_to_utf16(site.location.clone())),
+                );
+            }
+        }
+        if !locations.is_empty() {
+            return Ok(goto_response(locations));
+        }
+
+        let mut locations: Vec<Location> = self
+            .definitions
+            .iter()
+            .filter(|entry| entry.key() != &document_uri)
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .get(variable_name)
+                    .into_iter()
+                    .flatten()
+                    .map(|site| site.location.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        locations
+            .sort_by(|a, b| (a.uri.as_str(), a.range.start).cmp(&(b.uri.as_str(), b.range.start)));
+        Ok(goto_response(
+            locations
+                .into_iter()
+                .map(|location| self.location_to_utf16(location))
+                .collect(),
+        ))
+    }
+}
+
+fn goto_response(mut locations: Vec<Location>) -> Option<GotoDefinitionResponse> {
+    match locations.len() {
+        0 => None,
+        1 => locations.pop().map(GotoDefinitionResponse::Scalar),
+        _ => Some(GotoDefinitionResponse::Array(locations)),
+    }
+}
+
+struct TempScript(PathBuf);
+
+impl TempScript {
+    async fn create(text: &str) -> std::io::Result<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "klsp-{}-{}.k",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&path, text).await?;
+        Ok(TempScript(path))
+    }
+}
+
+impl Drop for TempScript {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn timed_out(timeout: Duration) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("timed out after {} ms", timeout.as_millis()),
+    )
+}
+
+// Feeds source to the interpreter's REPL so the value of each expression is printed
+async fn evaluate(
+    k_binary_path: &Path,
+    working_dir: Option<&Path>,
+    source: &str,
+    timeout: Duration,
+) -> std::io::Result<std::process::Output> {
+    let mut command = tokio::process::Command::new(k_binary_path);
+    command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(working_dir) = working_dir {
+        command.current_dir(working_dir);
+    }
+    let mut child = command.spawn()?;
+    let stdin = child.stdin.take();
+    let input = format!("{source}\n");
+    // Writes while the output is read, so a chatty script can't stall on a full pipe
+    let write = async move {
+        if let Some(mut stdin) = stdin {
+            tokio::io::AsyncWriteExt::write_all(&mut stdin, input.as_bytes()).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let run = async {
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        match written {
+            // The script may exit before reading all of its input
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err),
+            _ => output,
+        }
+    };
+    tokio::time::timeout(timeout, run)
+        .await
+        .unwrap_or_else(|_| Err(timed_out(timeout)))
+}
+
+async fn run_formatter(formatter_path: &Path, text: &str) -> std::io::Result<String> {
+    let mut child = tokio::process::Command::new(formatter_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::io::AsyncWriteExt::write_all(&mut stdin, text.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(std::io::Error::other)
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(%uri))]
+async fn get_diagnostics(
+    config: &Config,
+    uri: &Url,
+    working_dir: Option<&Path>,
+    text: &str,
+) -> Vec<Diagnostic> {
+    let Some(k_binary_path) = &config.k_binary_path else {
+        return vec![];
+    };
+
+    // Run a snapshot of the buffer so diagnostics don't wait for the file to be saved.
+    // The child is killed when the run times out or a newer edit aborts this task.
+    let output = match TempScript::create(text).await {
+        Ok(script) => {
+            let mut command = tokio::process::Command::new(k_binary_path);
+            command
+                .arg(&script.0)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::piped())
+                .kill_on_drop(true);
+            if let Some(working_dir) = working_dir {
+                command.current_dir(working_dir);
+            }
+            match command.spawn() {
+                Ok(child) => {
+                    tokio::time::timeout(config.diagnostics_timeout, child.wait_with_output())
+                        .await
+                        .unwrap_or_else(|_| Err(timed_out(config.diagnostics_timeout)))
+                }
+                Err(err) => Err(err),
+            }
+        }
+        Err(err) => Err(err),
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            return vec![Diagnostic {
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("k-language-server".to_string()),
+                message: format!(
+                    "Failed to run the K interpreter at {}: {err}",
+                    k_binary_path.display()
+                ),
+                ..Diagnostic::default()
+            }]
+        }
+    };
+
+    let stderr_output = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() || !stderr_output.trim().is_empty() {
+        let doc_lines: Vec<String> = text.split('\n').map(|x| x.trim_end().to_owned()).collect();
+        parse_diagnostics_from_stderr(stderr_output, &doc_lines, uri, config.diagnostic_severity)
+    } else {
+        vec![] // The interpreter accepted the buffer without complaint
+    }
+}
+
+fn error_message(block: &[&str], character: usize) -> String {
+    let reason = block
+        .iter()
+        .find_map(|line| line.trim().strip_prefix('\''))
+        .or_else(|| block.first().map(|line| line.trim()))
+        .unwrap_or("error");
+
+    // The caret line follows the echoed expression, so the offending token sits above it
+    let offending = match block {
+        [.., expression, caret] if caret.trim().starts_with('^') => {
+            // The caret line is spaces, so its column counts characters of the expression
+            let offset = expression
+                .char_indices()
+                .nth(character)
+                .map_or(expression.len(), |(offset, _)| offset);
+            lexer::tokenize(expression)
+                .into_iter()
+                .find(|token| offset < token.start + token.text.len())
+                .map(|token| token.text)
+        }
+        _ => None,
+    };
+    match offending {
+        // Symbols carry their own backticks, which need a longer fence
+        Some(token) if token.contains('`') => format!("{reason} at `` {token} ``"),
+        Some(token) => format!("{reason} at `{token}`"),
+        None => reason.to_string(),
+    }
+}
+
+fn parse_diagnostics_from_stderr(
+    stderr_output: String,
+    doc_lines: &[String],
+    uri: &Url,
+    severity: DiagnosticSeverity,
+) -> Vec<Diagnostic> {
+    tracing::debug!(stderr = %stderr_output, "interpreter reported errors");
+    let mut diagnostics = Vec::new();
+    // The caret indexes the echoed line, which may be indented differently from the document
+    let error_diagnostic = |block: &[&str], source: Option<(usize, isize)>, caret: usize| {
+        let (line_number, shift) = source.unwrap_or((0, 0));
+        // The caret counts characters of the echoed line, while ranges here are in bytes
+        let echoed = match block {
+            [.., echoed, _] => *echoed,
+            _ => "",
+        };
+        let (offset, width) = echoed.char_indices().nth(caret).map_or(
+            (caret + echoed.len() - echoed.chars().count(), 1),
+            |(offset, c)| (offset, c.len_utf8()),
+        );
+        let character = (offset as isize + shift).max(0) as u32;
+        let range = Range::new(
+            Position::new(line_number as u32, character),
+            Position::new(line_number as u32, character + width as u32),
+        );
+        Diagnostic::new(
+            range,
+            Some(severity),
+            None,
+            Some("k-language-server".to_string()),
+            error_message(block, caret),
+            Some(vec![DiagnosticRelatedInformation {
+                location: Location {
+                    uri: uri.clone(),
+                    range,
+                },
+                message: stderr_output.clone(),
+            }]),
+            None,
+        )
+    };
+
+    let mut block: Vec<&str> = Vec::new();
+    // Document line echoed in the current block, and the column shift from its echo
+    let mut source = None;
+    // Echoed expressions appear in source order, so each search resumes after the last match
+    let mut search_from = 0;
+
+    for line in stderr_output.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.trim().starts_with('\'') && block.iter().any(|l| !l.trim().starts_with('\'')) {
+            // A new error message starts before the previous block printed a caret
+            diagnostics.push(error_diagnostic(&block, source.take(), 0));
+            block.clear();
+        }
+        block.push(line);
+
+        if line.trim().starts_with('^') {
+            let caret = line.find('^').unwrap_or(0);
+            diagnostics.push(error_diagnostic(&block, source.take(), caret));
+            block.clear();
+        } else if !line.trim().starts_with('\'') {
+            let matches_line = |r: &String| r.trim() == line.trim();
+            if let Some(found) = doc_lines[search_from.min(doc_lines.len())..]
+                .iter()
+                .position(matches_line)
+                .map(|offset| search_from + offset)
+                .or_else(|| doc_lines.iter().position(matches_line))
+            {
+                let indent = |text: &str| (text.len() - text.trim_start().len()) as isize;
+                source = Some((found, indent(&doc_lines[found]) - indent(line)));
+                search_from = found + 1;
+            }
+        }
+    }
+    if !block.is_empty() {
+        diagnostics.push(error_diagnostic(&block, source, 0));
+    }
+
+    diagnostics
+}
+
+#[tokio::main]
+async fn main() {
+    // Logs go to stderr, since stdout carries the JSON-RPC stream
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = LOG_FILTER.set(handle);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_ansi(false)
+                .with_span_events(FmtSpan::CLOSE),
+        )
+        .init();
+
+    let (service, socket) = LspService::new(KLanguageServer::new);
     Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
         .serve(service)
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> LspService<KLanguageServer> {
+        LspService::new(KLanguageServer::new).0
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+    }
+
+    async fn open(server: &KLanguageServer, uri: &Url, text: &str) {
+        let text_document = TextDocumentItem::new(uri.clone(), "k".into(), 1, text.into());
+        server
+            .did_open(DidOpenTextDocumentParams { text_document })
+            .await;
+    }
+
+    async fn goto(server: &KLanguageServer, uri: &Url, position: Position) -> Option<Location> {
+        let params = GotoDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams::new(
+                TextDocumentIdentifier::new(uri.clone()),
+                position,
+            ),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        match server.goto_definition(params).await.unwrap()? {
+            GotoDefinitionResponse::Scalar(location) => Some(location),
+            _ => None,
+        }
+    }
+
+    async fn change(server: &KLanguageServer, uri: &Url, changes: &[(Option<Range>, &str)]) {
+        let content_changes = changes
+            .iter()
+            .map(|&(range, text)| TextDocumentContentChangeEvent {
+                range,
+                range_length: None,
+                text: text.to_string(),
+            })
+            .collect();
+        server
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes,
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn did_change_keeps_line_breaks() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/change.k").unwrap();
+        open(server, &uri, "a:1\nb:a+1\n").await;
+
+        server
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(range((1, 0), (1, 0))),
+                    range_length: None,
+                    text: "c:2\n".to_string(),
+                }],
+            })
+            .await;
+
+        assert_eq!(server.document_text(&uri).unwrap(), "a:1\nc:2\nb:a+1\n");
+        let location = goto(server, &uri, Position::new(2, 2)).await.unwrap();
+        assert_eq!(location.range, range((0, 0), (0, 1)));
+    }
+
+    #[tokio::test]
+    async fn did_change_keeps_other_documents_definitions() {
+        let service = server();
+        let server = service.inner();
+        let a = Url::parse("file:///tmp/a.k").unwrap();
+        let b = Url::parse("file:///tmp/b.k").unwrap();
+        open(server, &a, "f:{x+1}\n").await;
+        open(server, &b, "g:2\ng*3\n").await;
+
+        server
+            .did_change(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(a.clone(), 2),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "f:{x+2}\n".to_string(),
+                }],
+            })
+            .await;
+
+        assert!(server.definitions.contains_key(&b));
+        let location = goto(server, &b, Position::new(1, 0)).await.unwrap();
+        assert_eq!(location.uri, b);
+        assert_eq!(location.range, range((0, 0), (0, 1)));
+    }
+
+    #[tokio::test]
+    async fn did_change_applies_edits_across_lines_and_past_the_end() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/edits.k").unwrap();
+
+        open(server, &uri, "a:1\nb:2\nc:3\n").await;
+        change(server, &uri, &[(Some(range((0, 2), (2, 2))), "9")]).await;
+        assert_eq!(server.document_text(&uri).unwrap(), "a:93\n");
+
+        open(server, &uri, "a:1+2\n").await;
+        change(server, &uri, &[(Some(range((0, 3), (0, 3))), "\n\n")]).await;
+        assert_eq!(server.document_text(&uri).unwrap(), "a:1\n\n+2\n");
+
+        // Clients may send an end position beyond the last line
+        open(server, &uri, "a:1\nb:2\n").await;
+        change(server, &uri, &[(Some(range((1, 0), (5, 0))), "")]).await;
+        assert_eq!(server.document_text(&uri).unwrap(), "a:1\n");
+        assert!(!server.definitions.get(&uri).unwrap().contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn did_change_applies_batches_in_order() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/batch.k").unwrap();
+        open(server, &uri, "a:1\n").await;
+
+        // The second change addresses the text produced by the first
+        let changes = [
+            (Some(range((0, 2), (0, 3))), "10\nb:20"),
+            (Some(range((1, 0), (1, 1))), "c"),
+        ];
+        change(server, &uri, &changes).await;
+        assert_eq!(server.document_text(&uri).unwrap(), "a:10\nc:20\n");
+        let definitions = server.definitions.get(&uri).unwrap();
+        assert!(definitions.contains_key("c") && !definitions.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn did_change_batches_spanning_line_boundaries_keep_definitions_current() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/join.k").unwrap();
+        open(server, &uri, "f:1\ng:2\ng+f\n").await;
+
+        // Join the first two lines, then split them again around a new definition
+        let changes = [
+            (Some(range((0, 3), (1, 0))), ""),
+            (Some(range((0, 3), (0, 3))), "\nh:3\n"),
+        ];
+        change(server, &uri, &changes).await;
+        assert_eq!(server.document_text(&uri).unwrap(), "f:1\nh:3\ng:2\ng+f\n");
+        let location = goto(server, &uri, Position::new(3, 0)).await.unwrap();
+        assert_eq!(location.range, range((2, 0), (2, 1)));
+        let location = goto(server, &uri, Position::new(3, 2)).await.unwrap();
+        assert_eq!(location.range, range((0, 0), (0, 1)));
+    }
+
+    #[tokio::test]
+    async fn untitled_documents_keep_the_server_working() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        open(server, &uri, "a:1\n\\l lib.k\na+1\n").await;
+        change(server, &uri, &[(Some(range((2, 2), (2, 3))), "2")]).await;
+        // Let the diagnostics task run for the document that has no file path
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(server.document_text(&uri).unwrap(), "a:1\n\\l lib.k\na+2\n");
+        let location = goto(server, &uri, Position::new(2, 0)).await.unwrap();
+        assert_eq!(location.range, range((0, 0), (0, 1)));
+    }
+
+    #[tokio::test]
+    async fn did_close_forgets_the_document() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/closed.k").unwrap();
+        open(server, &uri, "a:1\n").await;
+        server
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier::new(uri.clone()),
+            })
+            .await;
+
+        assert!(!server.documents.contains_key(&uri));
+        assert!(!server.definitions.contains_key(&uri));
+    }
+
+    #[test]
+    fn find_occurrences_skips_string_literals() {
+        let text = "count:1\nmsg:\"count is \\\"count\\\" wrong\"\ncount+1\n";
+        assert_eq!(
+            find_occurrences(text, "count"),
+            vec![range((0, 0), (0, 5)), range((2, 0), (2, 5))]
+        );
+    }
+
+    // Ranges edited in each document by renaming the name at position
+    async fn rename(
+        server: &KLanguageServer,
+        uri: &Url,
+        position: Position,
+        new_name: &str,
+    ) -> HashMap<Url, Vec<Range>> {
+        let params = RenameParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: uri.clone() },
+                position,
+            },
+            new_name: new_name.to_string(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let edit = server.rename(params).await.unwrap().unwrap();
+        edit.changes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(uri, edits)| (uri, edits.into_iter().map(|edit| edit.range).collect()))
+            .collect()
+    }
+
+    fn stderr_ranges(text: &str, stderr: &str) -> Vec<Range> {
+        let doc_lines: Vec<String> = text.split('\n').map(|x| x.trim_end().to_owned()).collect();
+        let uri = Url::parse("file:///tmp/errors.k").unwrap();
+        let severity = DiagnosticSeverity::ERROR;
+        parse_diagnostics_from_stderr(stderr.to_string(), &doc_lines, &uri, severity)
+            .into_iter()
+            .map(|diagnostic| diagnostic.range)
+            .collect()
+    }
+
+    #[test]
+    fn stderr_errors_on_duplicate_lines_map_to_each_occurrence() {
+        let text = "f:{x+1}\nf `a\nf `a\n";
+        let stderr = "'type\nf `a\n  ^\n'type\nf `a\n  ^\n";
+        assert_eq!(
+            stderr_ranges(text, stderr),
+            vec![range((1, 2), (1, 3)), range((2, 2), (2, 3))]
+        );
+    }
+
+    #[test]
+    fn stderr_caret_accounts_for_indentation() {
+        let text = "g:1\n    f:{x+`a}\n";
+        let stderr = "'type\nf:{x+`a}\n     ^\n";
+        assert_eq!(stderr_ranges(text, stderr), vec![range((1, 9), (1, 10))]);
+
+        // The same expression at two indents keeps each block on its own line
+        let text = "h `a\n  h `a\n";
+        let stderr = "'type\nh `a\n  ^\n'type\nh `a\n  ^\n";
+        assert_eq!(
+            stderr_ranges(text, stderr),
+            vec![range((0, 2), (0, 3)), range((1, 4), (1, 5))]
+        );
+
+        // Ranges are in bytes until published, so the caret skips the width of each é
+        let text = "s:\"éé\";1+`a\n";
+        let stderr = "'type\ns:\"éé\";1+`a\n         ^\n";
+        assert_eq!(stderr_ranges(text, stderr), vec![range((0, 11), (0, 12))]);
+    }
+
+    fn apply_edits(text: &str, mut edits: Vec<TextEdit>) -> String {
+        let mut text = text.to_string();
+        edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+        for edit in edits.into_iter().rev() {
+            let start = offset_at(&text, edit.range.start);
+            let end = offset_at(&text, edit.range.end);
+            text.replace_range(start..end, &edit.new_text);
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn formatting_keeps_crlf_line_endings() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/crlf.k").unwrap();
+        let text = "a:  1\r\nbb:2\r\n";
+        open(server, &uri, text).await;
+
+        let params = DocumentFormattingParams {
+            text_document: TextDocumentIdentifier::new(uri),
+            options: FormattingOptions::default(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let edits = server.formatting(params).await.unwrap().unwrap();
+        assert_eq!(apply_edits(text, edits), format_text(text));
+        assert!(!format_text(text).contains("\r\r"));
+    }
+
+    #[tokio::test]
+    async fn formatting_aligns_definitions_and_trims_a_messy_script() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/messy.k").unwrap();
+        let text = "a:1   \nbb:  2\nccc:    3\n\nf: {x  +   1}\nf a\t\n";
+        open(server, &uri, text).await;
+
+        let params = DocumentFormattingParams {
+            text_document: TextDocumentIdentifier::new(uri),
+            options: FormattingOptions::default(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+        };
+        let edits = server.formatting(params).await.unwrap().unwrap();
+        let edited: Vec<(u32, &str)> = edits
+            .iter()
+            .map(|edit| (edit.range.start.line, edit.new_text.as_str()))
+            .collect();
+        // bb is already aligned with ccc, so only the other lines change
+        let expected = vec![(0, "a:   1"), (2, "ccc: 3"), (4, "f: {x + 1}"), (5, "f a")];
+        assert_eq!(edited, expected);
+        assert_eq!(
+            apply_edits(text, edits),
+            "a:   1\nbb:  2\nccc: 3\n\nf: {x + 1}\nf a\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn ranges_count_utf16_units_after_multibyte_characters() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/utf16.k").unwrap();
+        open(server, &uri, "café: 1\nx: café+1\ns:\"é\",$x\n").await;
+
+        let location = goto(server, &uri, Position::new(1, 4)).await.unwrap();
+        assert_eq!(location.range, range((0, 0), (0, 4)));
+
+        let params = ReferenceParams {
+            text_document_position: TextDocumentPositionParams::new(
+                TextDocumentIdentifier::new(uri.clone()),
+                Position::new(2, 7),
+            ),
+            context: ReferenceContext {
+                include_declaration: true,
+            },
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+        let references = server.references(params).await.unwrap().unwrap();
+        let ranges: Vec<Range> = references
+            .into_iter()
+            .map(|location| location.range)
+            .collect();
+        assert_eq!(ranges, vec![range((1, 0), (1, 1)), range((2, 7), (2, 8))]);
+    }
+
+    async fn type_character(
+        server: &KLanguageServer,
+        uri: &Url,
+        position: Position,
+        ch: &str,
+    ) -> Option<Vec<TextEdit>> {
+        let params = DocumentOnTypeFormattingParams {
+            text_document_position: TextDocumentPositionParams::new(
+                TextDocumentIdentifier::new(uri.clone()),
+                position,
+            ),
+            ch: ch.to_string(),
+            options: FormattingOptions {
+                tab_size: 2,
+                insert_spaces: true,
+                ..FormattingOptions::default()
+            },
+        };
+        server.on_type_formatting(params).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn on_type_formatting_handles_multibyte_text() {
+        let service = server();
+        let server = service.inner();
+        server.config.write().unwrap().space_after_semicolon = true;
+
+        let uri = Url::parse("file:///tmp/indent.k").unwrap();
+        open(server, &uri, "s:\"éééé\";f[\n").await;
+        let edits = type_character(server, &uri, Position::new(1, 0), "\n").await;
+        assert_eq!(edits.unwrap()[0].new_text, " ".repeat(12));
+
+        let uri = Url::parse("file:///tmp/semicolon.k").unwrap();
+        open(server, &uri, "f[\"é\";1]\n").await;
+        let edits = type_character(server, &uri, Position::new(0, 6), ";").await;
+        assert_eq!(edits.unwrap()[0].range, range((0, 6), (0, 6)));
+    }
+
+    #[test]
+    fn formatting_leaves_block_comments_alone() {
+        let text = "/\n  keep    these   spaces\n\\\na:  1\n";
+        assert_eq!(format_text(text), "/\n  keep    these   spaces\n\\\na: 1\n");
+    }
+
+    #[tokio::test]
+    async fn definitions_used_by_loading_files_are_not_unused() {
+        let service = server();
+        let server = service.inner();
+        let lib = Url::parse("file:///tmp/unused/lib.k").unwrap();
+        let util = Url::parse("file:///tmp/unused/util.k").unwrap();
+        let main = Url::parse("file:///tmp/unused/main.k").unwrap();
+        let lib_text = "f:{x+1}\ng:2\n";
+        open(server, &lib, lib_text).await;
+        open(server, &util, "\\l lib.k\n").await;
+        open(server, &main, "\\l util.k\nf 3\n").await;
+
+        let dependents: Vec<String> = server
+            .dependents(&lib)
+            .iter()
+            .filter_map(|dependent| server.document_text(dependent))
+            .collect();
+        let used = identifier_names(&dependents);
+        let unused: Vec<LSPAny> = unused_definition_diagnostics(lib_text, &used)
+            .into_iter()
+            .filter_map(|diagnostic| diagnostic.data)
+            .collect();
+        assert_eq!(unused, vec![LSPAny::from("g")]);
+    }
+
+    #[test]
+    fn common_builtin_functions_are_not_undefined() {
+        let text = "r: sum 1 2 3\nmax r\nexit 0\n";
+        assert!(undefined_name_diagnostics(text, &HashSet::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn undefined_names_follow_loads_transitively() {
+        let dir = std::env::temp_dir().join(format!("klsp-loads-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("util.k"), "\\l lib.k\n").unwrap();
+        std::fs::write(dir.join("lib.k"), "helper:{x+1}\n").unwrap();
+        let uri = Url::from_file_path(dir.join("main.k")).unwrap();
+        let text = "\\l util.k\nhelper 3\n";
+
+        let names = loaded_names(&uri, text, &DashMap::new()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(names.contains("helper"));
+        assert!(undefined_name_diagnostics(text, &names).is_empty());
+    }
+
+    // Writes an executable stand-in for the interpreter
+    fn fake_interpreter(name: &str, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("klsp-{name}-{}.sh", std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn evaluate_gives_up_after_the_timeout() {
+        let k = fake_interpreter("hang", "sleep 10");
+        let result = evaluate(&k, None, "1+1", Duration::from_millis(200)).await;
+        std::fs::remove_file(&k).unwrap();
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn evaluate_reads_output_while_writing_large_input() {
+        // Fills the stdout pipe before reading any input
+        let k = fake_interpreter("chatty", "head -c 200000 /dev/zero\nwc -c");
+        let source = "a".repeat(200_000);
+        let result = evaluate(&k, None, &source, Duration::from_secs(5)).await;
+        std::fs::remove_file(&k).unwrap();
+        let output = result.unwrap();
+        assert_eq!(output.stdout.len(), 200_000 + "200001\n".len());
+    }
+
+    #[tokio::test]
+    async fn workspace_is_indexed_after_initialize_returns() {
+        let dir = std::env::temp_dir().join(format!("klsp-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.k"), "f:{x+1}\n").unwrap();
+        let service = server();
+        let server = service.inner();
+
+        #[allow(deprecated)]
+        let params = InitializeParams {
+            root_uri: Some(Url::from_directory_path(&dir).unwrap()),
+            ..InitializeParams::default()
+        };
+        server.initialize(params).await.unwrap();
+        assert!(server.definitions.is_empty());
+        server.initialized(InitializedParams {}).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let lib = Url::from_file_path(dir.join("lib.k")).unwrap();
+        assert!(server.definitions.get(&lib).unwrap().contains_key("f"));
+    }
+
+    #[test]
+    fn error_messages_name_the_token_under_the_caret() {
+        let cases = [
+            (["'parse", "a:(1", "  ^"], 2, "parse at `(`"),
+            (["'type", "a+`b", "  ^"], 2, "type at `` `b ``"),
+            (["'type", "é+\"x\"", " ^"], 1, "type at `+`"),
+        ];
+        for (block, caret, message) in cases {
+            assert_eq!(error_message(&block, caret), message);
+        }
+    }
+
+    #[test]
+    fn selection_ranges_nest_through_multiline_lambdas() {
+        let chain = |text: &str, position: Position| {
+            let mut chain = Vec::new();
+            let mut selection = Some(Box::new(selection_range_at(text, position)));
+            while let Some(range) = selection {
+                chain.push(range.range);
+                selection = range.parent;
+            }
+            chain
+        };
+        let text = "f:{[a;b]\n  a+b\n  }\n";
+        let expected = vec![
+            range((1, 2), (1, 3)),
+            range((1, 0), (1, 5)),
+            range((0, 2), (2, 3)),
+            range((0, 0), (2, 3)),
+        ];
+        assert_eq!(chain(text, Position::new(1, 2)), expected);
+
+        let text = "f:{[a;b]\n  a+b\n  } 1 2\n";
+        let expected = vec![
+            range((0, 4), (0, 5)),
+            range((0, 3), (0, 8)),
+            range((0, 2), (2, 3)),
+            range((0, 0), (2, 3)),
+            range((0, 0), (2, 7)),
+        ];
+        assert_eq!(chain(text, Position::new(0, 4)), expected);
+    }
+
+    #[tokio::test]
+    async fn references_and_highlights_respect_lambda_scopes() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/scopes.k").unwrap();
+        open(server, &uri, "a:1\nf:{[a] a+1}\ng:{a*2}\n").await;
+
+        let references = |position: Position, include_declaration: bool| {
+            server.references(ReferenceParams {
+                text_document_position: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri: uri.clone() },
+                    position,
+                },
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+                context: ReferenceContext {
+                    include_declaration,
+                },
+            })
+        };
+        let ranges = |locations: Option<Vec<Location>>| -> Vec<Range> {
+            locations
+                .unwrap()
+                .into_iter()
+                .map(|location| location.range)
+                .collect()
+        };
+        let global = ranges(references(Position::new(0, 0), true).await.unwrap());
+        assert_eq!(global, vec![range((0, 0), (0, 1)), range((2, 3), (2, 4))]);
+        let parameter = ranges(references(Position::new(1, 7), false).await.unwrap());
+        assert_eq!(parameter, vec![range((1, 7), (1, 8))]);
+
+        let highlights = server
+            .document_highlight(DocumentHighlightParams {
+                text_document_position_params: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri: uri.clone() },
+                    position: Position::new(1, 7),
+                },
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let highlights: Vec<(Range, Option<DocumentHighlightKind>)> = highlights
+            .into_iter()
+            .map(|highlight| (highlight.range, highlight.kind))
+            .collect();
+        let expected = vec![
+            (range((1, 4), (1, 5)), Some(DocumentHighlightKind::WRITE)),
+            (range((1, 7), (1, 8)), Some(DocumentHighlightKind::READ)),
+        ];
+        assert_eq!(highlights, expected);
+    }
+
+    #[test]
+    fn names_after_multibyte_punctuation_are_extracted_whole() {
+        assert_eq!(extract_token_at_position("a:1—b", 6), (6, "b"));
+        assert_eq!(extract_variable_at_position("/ foo—bar", 8), "bar");
+        assert_eq!(extract_variable_at_position("x→total", 6), "total");
+    }
+
+    #[test]
+    fn writes_to_handles_are_not_definitions() {
+        let uri = Url::parse("file:///tmp/writes.k").unwrap();
+        assert!(parse("1:\"hello\\n\"\n0:`:f\n", &uri).is_empty());
+
+        let text = "a: \"10:30\"\nb: 1: \"x\"\n";
+        let removed = remove_definition_edit(text, Position::new(0, 0)).unwrap();
+        assert_eq!(removed.range, range((0, 0), (1, 0)));
+        let removed = remove_definition_edit(text, Position::new(1, 0)).unwrap();
+        assert_eq!(removed.range, range((1, 0), (1, 3)));
+    }
+
+    #[tokio::test]
+    async fn rename_leaves_implicit_parameters_alone() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/implicit.k").unwrap();
+        open(server, &uri, "x:5\nf:{x+1}\n").await;
+
+        let edits = |ranges: Vec<Range>| HashMap::from([(uri.clone(), ranges)]);
+        let global = rename(server, &uri, Position::new(0, 0), "n").await;
+        assert_eq!(global, edits(vec![range((0, 0), (0, 1))]));
+        let implicit = rename(server, &uri, Position::new(1, 3), "n").await;
+        assert_eq!(implicit, edits(vec![range((1, 3), (1, 4))]));
+    }
+
+    #[tokio::test]
+    async fn rename_stays_within_files_related_by_loads() {
+        let service = server();
+        let server = service.inner();
+        let lib = Url::parse("file:///tmp/proj/lib.k").unwrap();
+        let main = Url::parse("file:///tmp/proj/main.k").unwrap();
+        let other = Url::parse("file:///tmp/other/b.k").unwrap();
+        open(server, &lib, "n:1\n").await;
+        open(server, &main, "\\l lib.k\nn+1\n").await;
+        open(server, &other, "n:2\nn*3\n").await;
+
+        let edits = rename(server, &lib, Position::new(0, 0), "count").await;
+        let expected = HashMap::from([
+            (lib.clone(), vec![range((0, 0), (0, 1))]),
+            (main.clone(), vec![range((1, 0), (1, 1))]),
+        ]);
+        assert_eq!(edits, expected);
+        let edits = rename(server, &main, Position::new(1, 0), "count").await;
+        assert_eq!(edits, expected);
+    }
+
+    #[tokio::test]
+    async fn undefined_names_see_unsaved_loaded_buffers() {
+        let service = server();
+        let server = service.inner();
+        let lib = Url::parse("file:///tmp/unsaved/lib.k").unwrap();
+        let main = Url::parse("file:///tmp/unsaved/main.k").unwrap();
+        let text = "\\l lib.k\nhelper 3\n";
+        open(server, &lib, "helper:{x+1}\n").await;
+        open(server, &main, text).await;
+
+        let names = loaded_names(&main, text, &server.documents).await;
+        assert!(undefined_name_diagnostics(text, &names).is_empty());
+    }
+
+    #[tokio::test]
+    async fn commented_out_definitions_are_not_indexed() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/commented.k").unwrap();
+        open(server, &uri, "/ x: 5\n/x: 6\ny: 1 / x: 7\n").await;
+
+        let definitions = server.definitions.get(&uri).unwrap();
+        assert_eq!(definitions.keys().collect::<Vec<_>>(), vec!["y"]);
+    }
+
+    const COMMENTED: &str = "/ a: 1\n/\nb: 2\n  a: b\n\\\nc: 3\nc+1 / b\n";
+
+    #[test]
+    fn parse_skips_definitions_inside_comments() {
+        let uri = Url::parse("file:///tmp/comments.k").unwrap();
+        assert_eq!(parse(COMMENTED, &uri).keys().collect::<Vec<_>>(), vec!["c"]);
+    }
+
+    #[test]
+    fn find_occurrences_skips_comments() {
+        assert!(find_occurrences(COMMENTED, "a").is_empty());
+        assert!(find_occurrences(COMMENTED, "b").is_empty());
+        assert_eq!(
+            find_occurrences(COMMENTED, "c"),
+            vec![range((5, 0), (5, 1)), range((6, 0), (6, 1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn dotted_names_resolve_from_every_column() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/dotted.k").unwrap();
+        open(server, &uri, ".ns.x: 5\n1+.ns.x\n").await;
+
+        assert!(server.definitions.get(&uri).unwrap().contains_key(".ns.x"));
+        for column in 2..=7 {
+            let location = goto(server, &uri, Position::new(1, column)).await.unwrap();
+            assert_eq!(location.range, range((0, 0), (0, 5)), "column {column}");
+        }
+    }
+
+    #[tokio::test]
+    async fn goto_picks_the_nearest_preceding_definition() {
+        let service = server();
+        let server = service.inner();
+        let uri = Url::parse("file:///tmp/nearest.k").unwrap();
+        open(server, &uri, "f 1\nf:{x}\nf 2\nf:{y}\nf 3\n").await;
+
+        // Before the first definition, between the two, and after the last
+        let cases = [(0, (1, 0)), (2, (1, 0)), (4, (3, 0))];
+        for (line, start) in cases {
+            let location = goto(server, &uri, Position::new(line, 0)).await.unwrap();
+            assert_eq!(location.range, range(start, (start.0, 1)), "line {line}");
+        }
+    }
+
+    #[test]
+    fn missing_interpreter_paths_are_reported() {
+        assert!(interpreter_problem(None).is_some());
+        let message = interpreter_problem(Some(Path::new("/nonexistent/bin/k")));
+        assert!(message.unwrap().contains("/nonexistent/bin/k"));
+        assert!(interpreter_problem(Some(&std::env::current_exe().unwrap())).is_none());
+    }
+
+    #[test]
+    fn repeated_parses_reuse_the_compiled_definition_regex() {
+        let uri = Url::parse("file:///tmp/large.k").unwrap();
+        let text: String = (0..5000).map(|i| format!("name{i}: {i}\n")).collect();
+        let first = parse(&text, &uri);
+        let compiled = LazyLock::get(&DEFINITION_RE).map(|regex| regex as *const Regex);
+        assert!(compiled.is_some());
+        for _ in 0..10 {
+            assert_eq!(parse(&text, &uri).len(), first.len());
+            let regex = LazyLock::get(&DEFINITION_RE).map(|regex| regex as *const Regex);
+            assert_eq!(regex, compiled);
+        }
+        assert_eq!(first.len(), 5000);
+    }
+}
@@ -0,0 +1,47 @@
+use tower_lsp::lsp_types::{Position, Range};
+
+// LSP columns count UTF-16 code units, while the server slices lines by byte offset
+pub struct LineIndex<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str) -> Self {
+        LineIndex {
+            lines: text.lines().collect(),
+        }
+    }
+
+    fn line(&self, line: u32) -> &'a str {
+        self.lines.get(line as usize).copied().unwrap_or("")
+    }
+
+    pub fn to_utf16(&self, position: Position) -> Position {
+        let line = self.line(position.line);
+        let mut byte = (position.character as usize).min(line.len());
+        while !line.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        Position::new(position.line, line[..byte].encode_utf16().count() as u32)
+    }
+
+    pub fn to_bytes(&self, position: Position) -> Position {
+        let line = self.line(position.line);
+        let mut character = 0;
+        for (idx, c) in line.char_indices() {
+            if character >= position.character {
+                return Position::new(position.line, idx as u32);
+            }
+            character += c.len_utf16() as u32;
+        }
+        Position::new(position.line, line.len() as u32)
+    }
+
+    pub fn range_to_utf16(&self, range: Range) -> Range {
+        Range::new(self.to_utf16(range.start), self.to_utf16(range.end))
+    }
+
+    pub fn range_to_bytes(&self, range: Range) -> Range {
+        Range::new(self.to_bytes(range.start), self.to_bytes(range.end))
+    }
+}